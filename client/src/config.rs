@@ -0,0 +1,68 @@
+use std::sync::OnceLock;
+
+/// Where the config file is looked for when `--config` isn't given. It's fine
+/// for it not to exist.
+const DEFAULT_CONFIG_FILE: &str = "client.toml";
+const DEFAULT_SERVER_URL: &str = "http://localhost:8080";
+
+static SERVER_URL: OnceLock<String> = OnceLock::new();
+
+/// Works out the server URL from, in order of preference, `--server-url`,
+/// `CLIENT_SERVER_URL`, `server_url` in the config file (`--config`,
+/// `CLIENT_CONFIG` or `client.toml`) and the default. Must be called before
+/// anything talks to the server.
+///
+/// The file uses the same format as the GUI client's, but only simple
+/// `key = "value"` lines are understood.
+pub fn init() -> Result<(), String> {
+    let mut args = std::env::args().skip(1);
+    let mut flag_url = None;
+    let mut flag_config = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server-url" => flag_url = Some(args.next().ok_or("--server-url needs a value")?),
+            "--config" => flag_config = Some(args.next().ok_or("--config needs a value")?),
+            other => return Err(format!("Unknown argument {}", other)),
+        }
+    }
+
+    let config_file = flag_config.or_else(|| std::env::var("CLIENT_CONFIG").ok());
+    let file_url = match &config_file {
+        Some(path) => read_server_url(path)?,
+        None if std::path::Path::new(DEFAULT_CONFIG_FILE).exists() => {
+            read_server_url(DEFAULT_CONFIG_FILE)?
+        }
+        None => None,
+    };
+
+    let server_url = flag_url
+        .or_else(|| std::env::var("CLIENT_SERVER_URL").ok())
+        .or(file_url)
+        .unwrap_or_else(|| DEFAULT_SERVER_URL.to_string());
+    SERVER_URL
+        .set(server_url.trim_end_matches('/').to_string())
+        .map_err(|_| "Config is already loaded".to_string())
+}
+
+/// The full URL of an endpoint on the server.
+pub fn url(path: &str) -> String {
+    let server_url = SERVER_URL.get().expect("config::init wasn't called");
+    format!("{}{}", server_url, path)
+}
+
+fn read_server_url(path: &str) -> Result<Option<String>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path, e))?;
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid config line in {}: {}", path, line))?;
+        if key.trim() == "server_url" {
+            return Ok(Some(value.trim().trim_matches('"').to_string()));
+        }
+    }
+    Ok(None)
+}
@@ -1,6 +1,10 @@
+mod config;
+
 use reqwest::Client;
 use reqwest::cookie::Jar;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::{self, Write};
 use std::sync::Arc;
@@ -25,13 +29,23 @@ struct ChangePrice {
 #[derive(Serialize)]
 struct CreateCrypto {
     name: String,
-    price: i32,
+    /// Decimal numbers are sent as text so they arrive exactly as typed.
+    price: String,
 }
 #[derive(Serialize)]
 struct RemoveCrypto {
     name: String,
 }
 #[derive(Serialize)]
+struct GrantRole {
+    email: String,
+    role: String,
+}
+#[derive(Serialize)]
+struct RevokeRole {
+    email: String,
+}
+#[derive(Serialize)]
 struct AddPortfolio {
     password: String,
     name: String,
@@ -39,7 +53,7 @@ struct AddPortfolio {
 #[derive(Serialize)]
 struct DeletePortfolio {
     password: String,
-    name: String,
+    portfolio_id: i32,
 }
 #[derive(Deserialize, Debug)]
 struct FetchCryptoNames {
@@ -47,7 +61,7 @@ struct FetchCryptoNames {
 }
 #[derive(Deserialize, Debug)]
 struct FetchCryptoPrises {
-    price: i32,
+    price: String,
 }
 #[derive(Deserialize, Debug, Serialize)]
 struct FetchCryptoSpecifc {
@@ -55,13 +69,52 @@ struct FetchCryptoSpecifc {
 }
 #[derive(Deserialize, Debug, Serialize)]
 struct BuyCrypto {
-    portfolioname: String,
+    portfolio_id: i32,
     portfoliopassword: String,
     crypto_to_buy: String,
-    amount: i32,
+    amount: String,
+}
+#[derive(Deserialize, Debug, Serialize)]
+struct SellCrypto {
+    portfolio_id: i32,
+    portfoliopassword: String,
+    crypto_to_sell: String,
+    amount: String,
+}
+#[derive(Serialize)]
+struct PortfolioSummary {
+    portfolio_id: i32,
+    portfoliopassword: String,
+}
+#[derive(Serialize)]
+struct PortfolioHistory {
+    portfolio_id: i32,
+    portfoliopassword: String,
+    page: i64,
+}
+#[derive(Deserialize, Debug)]
+struct PortfolioListing {
+    id: i32,
+    name: String,
+    cash: String,
+}
+/// What the server sends back when a request fails.
+#[derive(Deserialize, Debug)]
+struct ErrorEnvelope {
+    error: ApiError,
+}
+#[derive(Deserialize, Debug)]
+struct ApiError {
+    /// Stable, meant to be matched on. See `server/src/apierror.rs`.
+    code: String,
+    message: String,
+    /// Only sent when the request broke validation rules.
+    #[serde(default)]
+    fields: BTreeMap<String, Vec<String>>,
 }
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    config::init()?;
     println!("Welcome to root managment");
     let cookie_jar = Arc::new(Jar::default());
     let client = build_client_with_cookies(cookie_jar.clone())?;
@@ -87,15 +140,16 @@ async fn login_flow(client: &Client) -> Result<(), Box<dyn std::error::Error>> {
     let login_data = Login { email, password };
 
     let res = client
-        .post("http://localhost:8080/api/login")
+        .post(config::url("/api/login"))
         .json(&login_data)
         .send()
         .await?;
 
     save_cookie(&res)?;
 
-    println!("Status: {}", res.status());
-    println!("Server response: {}", res.text().await?);
+    if !print_response(res).await? {
+        return Err("Login failed".into());
+    }
 
     Ok(())
 }
@@ -106,13 +160,14 @@ async fn register_flow(client: &Client) -> Result<(), Box<dyn std::error::Error>
     let reg_data = Register { email, password };
 
     let res = client
-        .post("http://localhost:8080/api/register")
+        .post(config::url("/api/register"))
         .json(&reg_data)
         .send()
         .await?;
 
-    println!("Status: {}", res.status());
-    println!("Response: {}", res.text().await?);
+    if !print_response(res).await? {
+        return Err("Registration failed".into());
+    }
     Ok(())
 }
 
@@ -124,13 +179,9 @@ async fn handle_commands(client: &Client) -> Result<(), Box<dyn std::error::Erro
         match cmd.trim() {
             "help" => println!("Available commands: help, logout"),
             "logout" => {
-                let res = client
-                    .post("http://localhost:8080/api/logout")
-                    .send()
-                    .await?;
+                let res = client.post(config::url("/api/logout")).send().await?;
 
-                println!("Logout status: {}", res.status());
-                println!("Response: {}", res.text().await?);
+                print_response(res).await?;
                 break;
             }
             "change price" => {
@@ -138,27 +189,22 @@ async fn handle_commands(client: &Client) -> Result<(), Box<dyn std::error::Erro
                     name: "bitcoin".to_string(),
                 };
                 let res = client
-                    .post("http://localhost:8080/api/root/changeprice")
+                    .post(config::url("/api/root/changeprice"))
                     .json(&change_data)
                     .send()
                     .await?;
-                println!("Status: {}", res.status());
-                println!("Response: {}", res.text().await?);
+                print_response(res).await?;
             }
             "get root" => {
-                let res = client
-                    .post("http://localhost:8080/api/getroot")
-                    .send()
-                    .await?;
-                println!("Status: {}", res.status());
-                println!("Response: {}", res.text().await?);
+                let res = client.post(config::url("/api/getroot")).send().await?;
+                print_response(res).await?;
             }
             "create crypto" => {
                 let crypto_name = input("Enter crypto name: ");
                 let crypto_price = loop {
                     let input_str = input("Enter crypto starting price: ");
-                    match input_str.trim().parse::<i32>() {
-                        Ok(price) => break price,
+                    match input_str.trim().parse::<f64>() {
+                        Ok(_) => break input_str.trim().to_string(),
                         Err(_) => println!("Invalid number, try again."),
                     }
                 };
@@ -169,26 +215,48 @@ async fn handle_commands(client: &Client) -> Result<(), Box<dyn std::error::Erro
                 };
 
                 let res = client
-                    .post("http://localhost:8080/api/root/addcrypto")
+                    .post(config::url("/api/root/addcrypto"))
                     .json(&crypto)
                     .send()
                     .await?;
 
-                println!("Status: {}", res.status());
-                let body = res.text().await?;
-                println!("Response: {}", body);
+                print_response(res).await?;
             }
             "remove crypto" => {
                 let crypto_name = input("Enter crypto name: ");
                 let crypto = RemoveCrypto { name: crypto_name };
                 let res = client
-                    .post("http://localhost:8080/api/root/removecrypto")
+                    .post(config::url("/api/root/removecrypto"))
                     .json(&crypto)
                     .send()
                     .await?;
 
-                println!("Status: {}", res.status());
-                println!("Status: {}", res.text().await?);
+                print_response(res).await?;
+            }
+            "grant role" => {
+                let email = input("Enter user email: ");
+                let role = input("Enter role (player, moderator, admin): ");
+                let grant = GrantRole { email, role };
+                let res = client
+                    .post(config::url("/api/root/grantrole"))
+                    .json(&grant)
+                    .send()
+                    .await?;
+                print_response(res).await?;
+            }
+            "revoke role" => {
+                let email = input("Enter user email: ");
+                let revoke = RevokeRole { email };
+                let res = client
+                    .post(config::url("/api/root/revokerole"))
+                    .json(&revoke)
+                    .send()
+                    .await?;
+                print_response(res).await?;
+            }
+            "list roles" => {
+                let res = client.post(config::url("/api/root/roles")).send().await?;
+                print_response(res).await?;
             }
             "add portfolio" => {
                 let portfolio_name = input("Enter portfolio name: ");
@@ -198,12 +266,11 @@ async fn handle_commands(client: &Client) -> Result<(), Box<dyn std::error::Erro
                     name: portfolio_name,
                 };
                 let res = client
-                    .post("http://localhost:8080/api/addportfolio")
+                    .post(config::url("/api/addportfolio"))
                     .json(&portfolio)
                     .send()
                     .await?;
-                println!("Status: {}", res.status());
-                println!("Rsponse : {}", res.text().await?);
+                print_response(res).await?;
             }
             "delete portfolio" => {
                 let sure = input("Are you sure you want to delete the portfolio(yes, no): ")
@@ -211,84 +278,156 @@ async fn handle_commands(client: &Client) -> Result<(), Box<dyn std::error::Erro
                 if sure == "no" {
                     break;
                 }
-                let portfolio_name = input("Enter portfolio name for verification: ");
+                let portfolio_id = input_portfolio_id("Enter portfolio id for verification: ");
                 let portfolio_password = input("Enter portfolio password for verification: ");
                 let portfolio = DeletePortfolio {
                     password: portfolio_password,
-                    name: portfolio_name,
+                    portfolio_id,
                 };
                 let res = client
-                    .post("http://localhost:8080/api/deleteportfolio")
+                    .post(config::url("/api/deleteportfolio"))
                     .json(&portfolio)
                     .send()
                     .await?;
-                println!("Status: {}", res.status());
-                println!("Rsponse : {}", res.text().await?);
+                print_response(res).await?;
             }
             "fetch crypto names" => {
                 let res = client
-                    .post("http://localhost:8080/api/fetch/cryptonames")
+                    .post(config::url("/api/fetch/cryptonames"))
                     .send()
                     .await?;
 
-                println!("Status: {}", res.status());
-
-                let crypto_names: Vec<FetchCryptoNames> = res.json().await?;
-                println!("JSON received: {:?}", crypto_names);
+                if let Some(crypto_names) = read_json::<Vec<FetchCryptoNames>>(res).await? {
+                    println!("JSON received: {:?}", crypto_names);
+                }
             }
             "fetch crypto prices" => {
                 let res = client
-                    .post("http://localhost:8080/api/fetch/cryptoprices")
+                    .post(config::url("/api/fetch/cryptoprices"))
                     .send()
                     .await?;
 
-                println!("Status: {}", res.status());
-
-                let crypto_names: Vec<FetchCryptoPrises> = res.json().await?;
-                println!("JSON received: {:?}", crypto_names);
+                if let Some(crypto_names) = read_json::<Vec<FetchCryptoPrises>>(res).await? {
+                    println!("JSON received: {:?}", crypto_names);
+                }
             }
             "fetch crypto specific" => {
                 let name = input("What crypto name to look for: ");
                 let specific = FetchCryptoSpecifc { name: name };
                 let res = client
-                    .post("http://localhost:8080/api/fetch/cryptospecific")
+                    .post(config::url("/api/fetch/cryptospecific"))
                     .json(&specific)
                     .send()
                     .await?;
 
-                println!("Status: {}", res.status());
-
-                let crypto_names: Vec<FetchCryptoSpecifc> = res.json().await?;
-                println!("JSON received: {:?}", crypto_names);
+                if let Some(crypto_names) = read_json::<Vec<FetchCryptoSpecifc>>(res).await? {
+                    println!("JSON received: {:?}", crypto_names);
+                }
             }
             "buy crypto" => {
                 let crypto_name =
                     input("Give the name of the crypto you won't to buy(full name): ");
                 let amount = loop {
                     let input_str = input("How many of this stocks would you like: ");
-                    match input_str.trim().parse::<i32>() {
-                        Ok(price) => break price,
+                    match input_str.trim().parse::<f64>() {
+                        Ok(_) => break input_str.trim().to_string(),
                         Err(_) => println!("Invalid number try again"),
                     }
                 };
-                let portfolio_name = input("To which portfolio you want to add it?: ");
+                let portfolio_id =
+                    input_portfolio_id("To which portfolio (id) you want to add it?: ");
                 let portfolio_password = input("Give me the password for this portfolio: ");
 
                 let crypto_buy = BuyCrypto {
-                    portfolioname: portfolio_name,
+                    portfolio_id,
                     portfoliopassword: portfolio_password,
                     crypto_to_buy: crypto_name,
                     amount: amount,
                 };
 
                 let res = client
-                    .post("http://localhost:8080/api/crypto/buycrypto")
+                    .post(config::url("/api/crypto/buycrypto"))
                     .json(&crypto_buy)
                     .send()
                     .await?;
-                println!("Status: {}", res.status());
-                let body = res.text().await?;
-                println!("Response: {}", body);
+                print_response(res).await?;
+            }
+            "sell crypto" => {
+                let crypto_name =
+                    input("Give the name of the crypto you want to sell(full name): ");
+                let amount = loop {
+                    let input_str = input("How many of this stocks would you like to sell: ");
+                    match input_str.trim().parse::<f64>() {
+                        Ok(_) => break input_str.trim().to_string(),
+                        Err(_) => println!("Invalid number try again"),
+                    }
+                };
+                let portfolio_id =
+                    input_portfolio_id("From which portfolio (id) you want to sell it?: ");
+                let portfolio_password = input("Give me the password for this portfolio: ");
+
+                let crypto_sell = SellCrypto {
+                    portfolio_id,
+                    portfoliopassword: portfolio_password,
+                    crypto_to_sell: crypto_name,
+                    amount,
+                };
+
+                let res = client
+                    .post(config::url("/api/crypto/sellcrypto"))
+                    .json(&crypto_sell)
+                    .send()
+                    .await?;
+                print_response(res).await?;
+            }
+            "list portfolios" => {
+                let res = client.post(config::url("/api/portfolios")).send().await?;
+                if let Some(portfolios) = read_json::<Vec<PortfolioListing>>(res).await? {
+                    if portfolios.is_empty() {
+                        println!("No portfolios yet.");
+                    }
+                    for portfolio in portfolios {
+                        println!("#{} {} ({})", portfolio.id, portfolio.name, portfolio.cash);
+                    }
+                }
+            }
+            "portfolio details" => {
+                let portfolio_id = input_portfolio_id("Enter portfolio id: ");
+                let res = client
+                    .post(config::url(&format!("/api/portfolios/{}", portfolio_id)))
+                    .send()
+                    .await?;
+                print_response(res).await?;
+            }
+            "portfolio summary" => {
+                let portfolio_id = input_portfolio_id("Enter portfolio id: ");
+                let portfolio_password = input("Enter portfolio password: ");
+                let summary = PortfolioSummary {
+                    portfolio_id,
+                    portfoliopassword: portfolio_password,
+                };
+                let res = client
+                    .post(config::url("/api/portfolio/summary"))
+                    .json(&summary)
+                    .send()
+                    .await?;
+                print_response(res).await?;
+            }
+            "portfolio history" => {
+                let portfolio_id = input_portfolio_id("Enter portfolio id: ");
+                let portfolio_password = input("Enter portfolio password: ");
+                let page = input("Enter page: ").trim().parse::<i64>().unwrap_or(1);
+                let history = PortfolioHistory {
+                    portfolio_id,
+                    portfoliopassword: portfolio_password,
+                    page,
+                };
+                let res = client
+                    .post(config::url("/api/portfolio/history"))
+                    .json(&history)
+                    .send()
+                    .await?;
+                print_response(res).await?;
             }
             _ => println!("Unknown command."),
         }
@@ -296,6 +435,43 @@ async fn handle_commands(client: &Client) -> Result<(), Box<dyn std::error::Erro
     Ok(())
 }
 
+/// Prints the status and what the server said. Returns whether the request worked.
+async fn print_response(res: reqwest::Response) -> Result<bool, reqwest::Error> {
+    println!("Status: {}", res.status());
+    if res.status().is_success() {
+        println!("Response: {}", res.text().await?);
+        return Ok(true);
+    }
+    print_error(res).await;
+    Ok(false)
+}
+
+/// Parses a successful JSON response, or prints the error the server sent.
+async fn read_json<T: DeserializeOwned>(
+    res: reqwest::Response,
+) -> Result<Option<T>, reqwest::Error> {
+    println!("Status: {}", res.status());
+    if res.status().is_success() {
+        return Ok(Some(res.json().await?));
+    }
+    print_error(res).await;
+    Ok(None)
+}
+
+async fn print_error(res: reqwest::Response) {
+    let status = res.status();
+    match res.json::<ErrorEnvelope>().await {
+        Ok(ErrorEnvelope { error }) => {
+            println!("Error ({}): {}", error.code, error.message);
+            for (field, problems) in &error.fields {
+                println!("  {}: {}", field, problems.join(", "));
+            }
+        }
+        // Not from the server itself, a proxy perhaps
+        Err(_) => println!("Error: {}", status),
+    }
+}
+
 fn build_client_with_cookies(cookie_jar: Arc<Jar>) -> Result<Client, reqwest::Error> {
     Client::builder().cookie_provider(cookie_jar).build()
 }
@@ -311,6 +487,16 @@ fn save_cookie(res: &reqwest::Response) -> Result<(), Box<dyn std::error::Error>
     Ok(())
 }
 
+/// Portfolios are addressed by the id the server gave them when they were added.
+fn input_portfolio_id(prompt: &str) -> i32 {
+    loop {
+        match input(prompt).parse::<i32>() {
+            Ok(id) if id > 0 => break id,
+            _ => println!("Invalid id, try again."),
+        }
+    }
+}
+
 fn input(prompt: &str) -> String {
     print!("{}", prompt);
     io::stdout().flush().unwrap();
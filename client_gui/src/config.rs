@@ -0,0 +1,68 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Where the config file is looked for when `--config` isn't given. It's fine
+/// for it not to exist.
+const DEFAULT_CONFIG_FILE: &str = "client.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Client settings. Each one is taken from, in order of preference, a command
+/// line flag, an environment variable, the TOML config file, and the default.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server_url: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_url: "http://localhost:8080".to_string(),
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Stock market game")]
+struct Cli {
+    /// TOML file to read settings from
+    #[arg(long, env = "CLIENT_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "CLIENT_SERVER_URL")]
+    server_url: Option<String>,
+}
+
+/// Reads the settings once at startup. Must be called before anything talks to
+/// the server.
+pub fn init() -> Result<(), String> {
+    let cli = Cli::parse();
+
+    let mut config = match &cli.config {
+        Some(path) => read_file(path)?,
+        None if std::path::Path::new(DEFAULT_CONFIG_FILE).exists() => {
+            read_file(DEFAULT_CONFIG_FILE.as_ref())?
+        }
+        None => Config::default(),
+    };
+    if let Some(server_url) = cli.server_url {
+        config.server_url = server_url;
+    }
+    config.server_url = config.server_url.trim_end_matches('/').to_string();
+
+    CONFIG
+        .set(config)
+        .map_err(|_| "Config is already loaded".to_string())
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config::init wasn't called")
+}
+
+fn read_file(path: &std::path::Path) -> Result<Config, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    toml::from_str(&contents).map_err(|e| format!("Invalid config in {}: {}", path.display(), e))
+}
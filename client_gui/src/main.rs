@@ -1,3 +1,4 @@
+mod config;
 mod helpers;
 mod send_to_server;
 mod structs;
@@ -32,6 +33,10 @@ enum Showburgermenu {
 // ------------------ MAIN ------------------
 
 fn main() {
+    if let Err(e) = config::init() {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let ttf_context = sdl2::ttf::init().unwrap();
@@ -53,6 +58,7 @@ fn main() {
     let mut current_view = View::Login;
     let mut login_result_rx: Option<Receiver<bool>> = None;
     let mut register_result_rx: Option<Receiver<bool>> = None;
+    let mut portfolios_rx: Option<Receiver<Vec<send_to_server::PortfolioListing>>> = None;
     let image_context = sdl2::image::init(InitFlag::PNG | InitFlag::JPG).unwrap();
     let texture = texture_creator
         .load_texture("src/assets/burger_menu.png")
@@ -63,6 +69,8 @@ fn main() {
         Subburgerbuttons::new((255, 255, 255), "Add portfolio".to_string()),
         Subburgerbuttons::new((255, 255, 255), "Remove portfolio".to_string()),
     ]]);
+    // The player's portfolios are listed below these
+    let burger_actions = burger_menu.fields.len();
     let button_login = Button::new(
         860,
         620,
@@ -222,6 +230,7 @@ fn main() {
                                 current_burger_view = Showburgermenu::Noshow;
                             } else {
                                 current_burger_view = Showburgermenu::Show;
+                                portfolios_rx = Some(spawn_portfolio_fetch());
                             }
                         }
                     }
@@ -332,6 +341,7 @@ fn main() {
             if let Ok(success) = rx.try_recv() {
                 if success {
                     current_view = View::MainScreen;
+                    portfolios_rx = Some(spawn_portfolio_fetch());
                 } else {
                     println!("Login failed");
                 }
@@ -342,12 +352,30 @@ fn main() {
             if let Ok(success) = rx.try_recv() {
                 if success {
                     current_view = View::MainScreen;
+                    portfolios_rx = Some(spawn_portfolio_fetch());
                 } else {
                     println!("Register failed");
                 }
                 register_result_rx = None;
             }
         }
+        if let Some(rx) = &portfolios_rx
+            && let Ok(portfolios) = rx.try_recv()
+        {
+            burger_menu.fields.truncate(burger_actions);
+            burger_menu.populate(vec![
+                portfolios
+                    .into_iter()
+                    .map(|p| {
+                        Subburgerbuttons::new(
+                            (255, 255, 255),
+                            format!("#{} {} ({})", p.id, p.name, p.cash),
+                        )
+                    })
+                    .collect(),
+            ]);
+            portfolios_rx = None;
+        }
 
         // DRAWING
         helpers::bg_color(&mut canvas, vec![8, 65, 92]);
@@ -406,3 +434,18 @@ fn main() {
         thread::sleep(Duration::from_millis(16));
     }
 }
+
+/// Fetches the player's portfolios in the background. Nothing is sent if it fails.
+fn spawn_portfolio_fetch() -> Receiver<Vec<send_to_server::PortfolioListing>> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        match rt.block_on(send_to_server::fetch_portfolios()) {
+            Ok(portfolios) => {
+                let _ = tx.send(portfolios);
+            }
+            Err(e) => println!("Fetching portfolios failed: {}", e),
+        }
+    });
+    rx
+}
@@ -1,51 +1,105 @@
+use crate::config;
 use reqwest::cookie::Jar;
 use reqwest::{Client, Response};
+use serde::Deserialize;
 use serde_json;
 use std::error::Error;
 use std::fs;
 use std::sync::Arc;
 use std::time::SystemTime;
-const BASE_URL: &str = "http://localhost:8080";
 
-pub async fn send_login_data(email: String, password: String) -> Result<(), Box<dyn Error>> {
-    println!("send_login_data function called");
-    let res = send_credentials("/api/login", email, password).await?;
-    println!("Login response: {}", res);
+/// What the server sends back when a request fails.
+#[derive(Deserialize, Debug)]
+struct ErrorEnvelope {
+    error: ApiError,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApiError {
+    /// Stable, meant to be matched on, like `invalid_credentials` or `email_taken`.
+    pub code: String,
+    pub message: String,
+}
 
-    if res.trim() != "Login successful" {
-        return Err("Login failed".into());
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
     }
+}
 
-    Ok(())
+impl Error for ApiError {}
+
+/// One of the logged in player's portfolios, as listed by `/api/portfolios`.
+#[derive(Deserialize, Debug)]
+pub struct PortfolioListing {
+    pub id: i32,
+    pub name: String,
+    pub cash: String,
+}
+
+pub async fn send_login_data(email: String, password: String) -> Result<(), Box<dyn Error>> {
+    println!("send_login_data function called");
+    let res = send_credentials("/api/login", email, password).await;
+    if let Err(e) = &res {
+        println!("Login failed: {}", e);
+    }
+    res
 }
 
 pub async fn send_register_data(email: String, password: String) -> Result<(), Box<dyn Error>> {
     println!("send_register_data function called");
-    let res = send_credentials("/api/register", email, password).await?;
-    println!("Register response: {}", res);
-    if res.trim() != "Register successful" {
-        return Err("Registration failed".into());
+    let res = send_credentials("/api/register", email, password).await;
+    if let Err(e) = &res {
+        println!("Registration failed: {}", e);
     }
-    Ok(())
+    res
 }
 
+/// Fails with the server's `ApiError` if it refused the request.
 async fn send_credentials(
     endpoint: &str,
     email: String,
     password: String,
-) -> Result<String, Box<dyn Error>> {
+) -> Result<(), Box<dyn Error>> {
     let client = build_client_with_cookies().await?;
 
     let res = client
-        .post(&format!("{}{}", BASE_URL, endpoint))
+        .post(format!("{}{}", config::get().server_url, endpoint))
         .json(&serde_json::json!({ "email": email, "password": password }))
         .send()
         .await?;
 
+    if !res.status().is_success() {
+        return Err(refusal(res).await);
+    }
+
     save_cookie(&res).await?; // <- this line ensures the cookie is saved
+    Ok(())
+}
+
+/// Lists the portfolios of whoever the saved cookie belongs to.
+pub async fn fetch_portfolios() -> Result<Vec<PortfolioListing>, Box<dyn Error>> {
+    let client = build_client_with_cookies().await?;
 
-    let body = res.text().await?;
-    Ok(body)
+    let res = client
+        .post(format!("{}/api/portfolios", config::get().server_url))
+        .header("cookie", load_cookie()?)
+        .send()
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(refusal(res).await);
+    }
+    Ok(res.json().await?)
+}
+
+/// The `ApiError` the server answered with.
+async fn refusal(res: Response) -> Box<dyn Error> {
+    let status = res.status();
+    match res.json::<ErrorEnvelope>().await {
+        Ok(ErrorEnvelope { error }) => error.into(),
+        Err(_) => format!("Server answered {}", status).into(),
+    }
 }
 
 /// Builds HTTP client with cookie handling
@@ -72,3 +126,18 @@ async fn save_cookie(res: &Response) -> Result<(), Box<dyn Error>> {
     }
     Ok(())
 }
+
+/// Reads back the `name=value` pair `save_cookie` wrote.
+fn load_cookie() -> Result<String, Box<dyn Error>> {
+    let saved = fs::read_to_string("src/cookie/cookie.txt")?;
+    let set_cookie = saved
+        .split_once(" | ")
+        .map(|(_, set_cookie)| set_cookie)
+        .ok_or("No cookie saved")?;
+    Ok(set_cookie
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_string())
+}
@@ -0,0 +1,12 @@
+DROP TABLE IF EXISTS transactions;
+DROP FUNCTION IF EXISTS transactions_append_only();
+DROP TABLE IF EXISTS fees;
+DROP TABLE IF EXISTS service_nonces;
+DROP TABLE IF EXISTS leaderboard_snapshots;
+DROP TABLE IF EXISTS price_ticks;
+DROP TABLE IF EXISTS orders;
+DROP TABLE IF EXISTS holdings;
+DROP TABLE IF EXISTS portfolios;
+DROP TABLE IF EXISTS crypto;
+DROP TABLE IF EXISTS token;
+DROP TABLE IF EXISTS users;
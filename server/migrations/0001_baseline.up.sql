@@ -0,0 +1,167 @@
+-- The schema as the server used to build it at startup. Everything is guarded so
+-- a database created by those older versions is adopted as is, and brought up to
+-- date where it was missing later additions.
+
+CREATE TABLE IF NOT EXISTS users (
+    id SERIAL PRIMARY KEY,
+    email VARCHAR(255) UNIQUE NOT NULL,
+    password VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role VARCHAR(16) NOT NULL DEFAULT 'player'
+    CHECK (role IN ('player', 'moderator', 'admin'));
+
+-- Whitelisted users became admins when roles replaced the whitelist
+DO $$
+BEGIN
+    IF to_regclass('whitelist') IS NOT NULL THEN
+        UPDATE users SET role = 'admin' WHERE email IN (SELECT email FROM whitelist);
+        DROP TABLE whitelist;
+    END IF;
+END $$;
+
+CREATE TABLE IF NOT EXISTS token (
+    token VARCHAR(255) NOT NULL,
+    owner VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+ALTER TABLE token ADD COLUMN IF NOT EXISTS last_seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+CREATE TABLE IF NOT EXISTS crypto (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    creator VARCHAR(255) NOT NULL,
+    price INT4 NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS portfolios (
+    id SERIAL PRIMARY KEY,
+    money INT4 NOT NULL,
+    owner VARCHAR(255),
+    name VARCHAR(255),
+    assets VARCHAR(255),
+    password VARCHAR(255),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+ALTER TABLE portfolios ADD COLUMN IF NOT EXISTS starting_money INT4 NOT NULL DEFAULT 1000;
+ALTER TABLE portfolios ADD COLUMN IF NOT EXISTS realized_pnl INT4 NOT NULL DEFAULT 0;
+
+-- One row per lot, so the price every coin was bought at is kept
+CREATE TABLE IF NOT EXISTS holdings (
+    id SERIAL PRIMARY KEY,
+    portfolio_id INT4 NOT NULL REFERENCES portfolios(id) ON DELETE CASCADE,
+    crypto_id INT4 NOT NULL REFERENCES crypto(id),
+    amount INT4 NOT NULL CHECK (amount > 0),
+    price_bought INT4 NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS holdings_portfolio_crypto ON holdings(portfolio_id, crypto_id);
+
+-- Open limit orders reserve money (buys) or coins (sells) until they fill or get cancelled.
+-- Stop-loss and take-profit orders keep their trigger in limit_price and reserve nothing.
+CREATE TABLE IF NOT EXISTS orders (
+    id SERIAL PRIMARY KEY,
+    portfolio_id INT4 NOT NULL REFERENCES portfolios(id) ON DELETE CASCADE,
+    crypto_id INT4 NOT NULL REFERENCES crypto(id) ON DELETE CASCADE,
+    side VARCHAR(4) NOT NULL CHECK (side IN ('buy', 'sell')),
+    amount INT4 NOT NULL CHECK (amount > 0),
+    limit_price INT4 NOT NULL CHECK (limit_price > 0),
+    kind VARCHAR(16) NOT NULL DEFAULT 'limit',
+    status VARCHAR(16) NOT NULL DEFAULT 'open',
+    fill_price INT4,
+    filled_amount INT4,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    closed_at TIMESTAMPTZ
+);
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS kind VARCHAR(16) NOT NULL DEFAULT 'limit';
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS filled_amount INT4;
+-- Money held back by a limit buy, fees included. Older orders only reserved amount * limit_price.
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS reserved INT8;
+CREATE INDEX IF NOT EXISTS orders_open ON orders(crypto_id) WHERE status = 'open';
+
+-- Every price the middleware sets, so history isn't lost when crypto.price is overwritten
+CREATE TABLE IF NOT EXISTS price_ticks (
+    id BIGSERIAL PRIMARY KEY,
+    crypto_id INT4 NOT NULL REFERENCES crypto(id) ON DELETE CASCADE,
+    price INT4 NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS price_ticks_crypto_time ON price_ticks(crypto_id, created_at);
+
+-- One row per portfolio per day, so rank movement can be shown
+CREATE TABLE IF NOT EXISTS leaderboard_snapshots (
+    day DATE NOT NULL,
+    portfolio_id INT4 NOT NULL REFERENCES portfolios(id) ON DELETE CASCADE,
+    owner VARCHAR(255),
+    starting_money INT4 NOT NULL,
+    total_value INT8 NOT NULL,
+    rank INT8 NOT NULL,
+    PRIMARY KEY (day, portfolio_id)
+);
+
+-- Nonces of signed service requests, kept just long enough to refuse replays
+CREATE TABLE IF NOT EXISTS service_nonces (
+    key_id VARCHAR(64) NOT NULL,
+    nonce VARCHAR(64) NOT NULL,
+    seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (key_id, nonce)
+);
+
+-- A row with no crypto_id is the global schedule, coins with their own row override it
+CREATE TABLE IF NOT EXISTS fees (
+    id SERIAL PRIMARY KEY,
+    crypto_id INT4 REFERENCES crypto(id) ON DELETE CASCADE,
+    flat_fee INT4 NOT NULL DEFAULT 0 CHECK (flat_fee >= 0),
+    percent_bps INT4 NOT NULL DEFAULT 0 CHECK (percent_bps BETWEEN 0 AND 10000),
+    spread_bps INT4 NOT NULL DEFAULT 0 CHECK (spread_bps BETWEEN 0 AND 10000)
+);
+CREATE UNIQUE INDEX IF NOT EXISTS fees_crypto ON fees((COALESCE(crypto_id, 0)));
+-- Enough friction that churning on every small move doesn't pay
+INSERT INTO fees (crypto_id, flat_fee, percent_bps, spread_bps) VALUES (NULL, 1, 25, 0)
+    ON CONFLICT DO NOTHING;
+
+-- Append-only ledger of everything that moved a portfolio's cash or coins.
+-- crypto_id has no foreign key so the history outlives a removed coin.
+CREATE TABLE IF NOT EXISTS transactions (
+    id BIGSERIAL PRIMARY KEY,
+    portfolio_id INT4 NOT NULL REFERENCES portfolios(id) ON DELETE CASCADE,
+    kind VARCHAR(16) NOT NULL CHECK (kind IN ('buy', 'sell', 'deposit', 'adjustment', 'import')),
+    crypto_id INT4,
+    crypto VARCHAR(255),
+    quantity INT4 NOT NULL DEFAULT 0,
+    price INT4 NOT NULL DEFAULT 0,
+    fee INT4 NOT NULL DEFAULT 0,
+    cash_change INT4 NOT NULL,
+    balance INT4 NOT NULL,
+    note VARCHAR(255),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS transactions_portfolio ON transactions(portfolio_id, id);
+
+-- Rows can't be changed, and only go away together with their portfolio
+CREATE OR REPLACE FUNCTION transactions_append_only() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND pg_trigger_depth() > 1 THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'transactions is append-only';
+END;
+$$ LANGUAGE plpgsql;
+DROP TRIGGER IF EXISTS transactions_append_only ON transactions;
+CREATE TRIGGER transactions_append_only BEFORE UPDATE OR DELETE ON transactions
+    FOR EACH ROW EXECUTE FUNCTION transactions_append_only();
+
+-- Portfolios from before the ledger get an opening balance and their current
+-- lots, so replaying the ledger ends where the portfolio is now
+CREATE TEMPORARY TABLE unledgered ON COMMIT DROP AS
+    SELECT id, money FROM portfolios p
+    WHERE NOT EXISTS (SELECT 1 FROM transactions t WHERE t.portfolio_id = p.id);
+INSERT INTO transactions (portfolio_id, kind, cash_change, balance, note)
+    SELECT id, 'deposit', money, money, 'Opening balance' FROM unledgered ORDER BY id;
+INSERT INTO transactions (portfolio_id, kind, crypto_id, crypto, quantity, price, cash_change, balance, note)
+    SELECT h.portfolio_id, 'import', h.crypto_id, c.name, h.amount, h.price_bought, 0, u.money, 'Opening balance'
+    FROM holdings h
+    JOIN unledgered u ON u.id = h.portfolio_id
+    JOIN crypto c ON c.id = h.crypto_id
+    ORDER BY h.portfolio_id, h.created_at, h.id;
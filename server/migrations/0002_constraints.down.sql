@@ -0,0 +1,19 @@
+ALTER TABLE orders
+    DROP CONSTRAINT orders_status_check,
+    DROP CONSTRAINT orders_kind_check;
+
+ALTER TABLE portfolios
+    DROP CONSTRAINT portfolios_money_check,
+    DROP CONSTRAINT portfolios_owner_fkey,
+    ALTER COLUMN password DROP NOT NULL,
+    ALTER COLUMN name DROP NOT NULL,
+    ALTER COLUMN owner DROP NOT NULL,
+    ADD COLUMN assets VARCHAR(255);
+
+ALTER TABLE crypto
+    DROP CONSTRAINT crypto_price_check,
+    DROP CONSTRAINT crypto_name_key;
+
+ALTER TABLE token
+    DROP CONSTRAINT token_owner_fkey,
+    DROP CONSTRAINT token_pkey;
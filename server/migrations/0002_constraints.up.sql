@@ -0,0 +1,71 @@
+-- crypto.name was never unique. Duplicates are merged into the oldest coin of that
+-- name, so lots, orders and price history all point at one row. The ledger keeps
+-- the old ids, it is append-only and has the name alongside.
+CREATE TEMPORARY TABLE duplicate_cryptos ON COMMIT DROP AS
+    SELECT c.id, k.id AS keep_id
+    FROM crypto c
+    JOIN LATERAL (SELECT MIN(id) AS id FROM crypto WHERE name = c.name) k ON k.id <> c.id;
+UPDATE holdings h SET crypto_id = d.keep_id FROM duplicate_cryptos d WHERE h.crypto_id = d.id;
+UPDATE orders o SET crypto_id = d.keep_id FROM duplicate_cryptos d WHERE o.crypto_id = d.id;
+UPDATE price_ticks t SET crypto_id = d.keep_id FROM duplicate_cryptos d WHERE t.crypto_id = d.id;
+-- Fees are one row per coin, the kept coin's own or else the oldest duplicate's win
+DELETE FROM fees f USING duplicate_cryptos d
+    WHERE f.crypto_id = d.id AND EXISTS (
+        SELECT 1 FROM fees g
+        WHERE g.crypto_id = d.keep_id
+            OR g.crypto_id IN (SELECT e.id FROM duplicate_cryptos e WHERE e.keep_id = d.keep_id AND e.id < d.id)
+    );
+UPDATE fees f SET crypto_id = d.keep_id FROM duplicate_cryptos d WHERE f.crypto_id = d.id;
+DELETE FROM crypto WHERE id IN (SELECT id FROM duplicate_cryptos);
+UPDATE crypto SET price = 0 WHERE price < 0;
+
+-- Lots still sitting in the old portfolios.assets column become holdings, as the
+-- startup importer used to do, before the column goes away. Coins that no longer
+-- exist are dropped. Invalid JSON stops the migration so it can be fixed by hand.
+CREATE TEMPORARY TABLE legacy_lots ON COMMIT DROP AS
+    SELECT p.id AS portfolio_id, p.money, c.id AS crypto_id, c.name, lot.amount, lot.price_bought
+    FROM portfolios p
+    CROSS JOIN LATERAL jsonb_to_recordset(p.assets::jsonb) AS lot(name TEXT, amount INT4, price_bought INT4)
+    JOIN crypto c ON c.name = lot.name
+    WHERE btrim(p.assets) <> '' AND lot.amount > 0;
+INSERT INTO holdings (portfolio_id, crypto_id, amount, price_bought)
+    SELECT portfolio_id, crypto_id, amount, price_bought FROM legacy_lots;
+INSERT INTO transactions (portfolio_id, kind, crypto_id, crypto, quantity, price, cash_change, balance, note)
+    SELECT portfolio_id, 'import', crypto_id, name, amount, price_bought, 0, money, 'Legacy portfolio assets'
+    FROM legacy_lots ORDER BY portfolio_id;
+ALTER TABLE portfolios DROP COLUMN assets;
+
+-- Sessions of users that no longer exist can't be used anyway
+DELETE FROM token WHERE owner NOT IN (SELECT email FROM users);
+ALTER TABLE token
+    ADD PRIMARY KEY (token),
+    ADD CONSTRAINT token_owner_fkey FOREIGN KEY (owner) REFERENCES users(email) ON DELETE CASCADE;
+
+-- Portfolios without an existing owner can't be reached by anyone. Nameless ones
+-- are named after their id, and ones without a password stay locked as before.
+DELETE FROM portfolios WHERE owner IS NULL OR owner NOT IN (SELECT email FROM users);
+UPDATE portfolios SET name = 'Portfolio ' || id WHERE name IS NULL;
+UPDATE portfolios SET password = '' WHERE password IS NULL;
+-- Changing a balance would no longer match the ledger, so this is left to be fixed by hand
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM portfolios WHERE money < 0) THEN
+        RAISE EXCEPTION 'Portfolios with negative money: %',
+            (SELECT string_agg(id::text, ', ' ORDER BY id) FROM portfolios WHERE money < 0);
+    END IF;
+END $$;
+
+ALTER TABLE crypto
+    ADD CONSTRAINT crypto_name_key UNIQUE (name),
+    ADD CONSTRAINT crypto_price_check CHECK (price >= 0);
+
+ALTER TABLE portfolios
+    ALTER COLUMN owner SET NOT NULL,
+    ALTER COLUMN name SET NOT NULL,
+    ALTER COLUMN password SET NOT NULL,
+    ADD CONSTRAINT portfolios_owner_fkey FOREIGN KEY (owner) REFERENCES users(email) ON DELETE CASCADE,
+    ADD CONSTRAINT portfolios_money_check CHECK (money >= 0);
+
+ALTER TABLE orders
+    ADD CONSTRAINT orders_kind_check CHECK (kind IN ('limit', 'stop_loss', 'take_profit')),
+    ADD CONSTRAINT orders_status_check CHECK (status IN ('open', 'filled', 'cancelled', 'rejected'));
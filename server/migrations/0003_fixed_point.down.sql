@@ -0,0 +1,45 @@
+-- Whole units can't hold fractions of a coin, so refuse rather than make them up
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM holdings WHERE amount <> trunc(amount))
+        OR EXISTS (SELECT 1 FROM orders WHERE status = 'open' AND amount <> trunc(amount)) THEN
+        RAISE EXCEPTION 'Portfolios hold fractional coins, sell them before reverting';
+    END IF;
+END $$;
+
+-- Everything else is rounded to whole units, which the ledger may no longer replay to
+ALTER TABLE transactions
+    ALTER COLUMN quantity TYPE INT4 USING round(quantity),
+    ALTER COLUMN price TYPE INT4 USING round(price),
+    ALTER COLUMN fee TYPE INT4 USING round(fee),
+    ALTER COLUMN cash_change TYPE INT4 USING round(cash_change),
+    ALTER COLUMN balance TYPE INT4 USING round(balance);
+
+ALTER TABLE fees
+    ALTER COLUMN flat_fee TYPE INT4 USING round(flat_fee);
+
+ALTER TABLE leaderboard_snapshots
+    ALTER COLUMN starting_money TYPE INT4 USING round(starting_money),
+    ALTER COLUMN total_value TYPE INT8 USING round(total_value);
+
+ALTER TABLE price_ticks
+    ALTER COLUMN price TYPE INT4 USING round(price);
+
+ALTER TABLE orders
+    ALTER COLUMN amount TYPE INT4 USING ceil(amount),
+    ALTER COLUMN limit_price TYPE INT4 USING greatest(round(limit_price), 1),
+    ALTER COLUMN fill_price TYPE INT4 USING round(fill_price),
+    ALTER COLUMN filled_amount TYPE INT4 USING round(filled_amount),
+    ALTER COLUMN reserved TYPE INT8 USING ceil(reserved);
+
+ALTER TABLE holdings
+    ALTER COLUMN amount TYPE INT4 USING amount,
+    ALTER COLUMN price_bought TYPE INT4 USING round(price_bought);
+
+ALTER TABLE crypto
+    ALTER COLUMN price TYPE INT4 USING round(price);
+
+ALTER TABLE portfolios
+    ALTER COLUMN money TYPE INT4 USING floor(money),
+    ALTER COLUMN starting_money TYPE INT4 USING round(starting_money),
+    ALTER COLUMN realized_pnl TYPE INT4 USING round(realized_pnl);
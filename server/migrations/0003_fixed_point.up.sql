@@ -0,0 +1,38 @@
+-- Money moves from whole units to exact decimals. Cash, prices and fees are kept
+-- to the cent and quantities of coins to a hundred-millionth.
+
+ALTER TABLE portfolios
+    ALTER COLUMN money TYPE NUMERIC(20, 2),
+    ALTER COLUMN starting_money TYPE NUMERIC(20, 2),
+    ALTER COLUMN realized_pnl TYPE NUMERIC(20, 2);
+
+ALTER TABLE crypto
+    ALTER COLUMN price TYPE NUMERIC(20, 2);
+
+ALTER TABLE holdings
+    ALTER COLUMN amount TYPE NUMERIC(20, 8),
+    ALTER COLUMN price_bought TYPE NUMERIC(20, 2);
+
+ALTER TABLE orders
+    ALTER COLUMN amount TYPE NUMERIC(20, 8),
+    ALTER COLUMN limit_price TYPE NUMERIC(20, 2),
+    ALTER COLUMN fill_price TYPE NUMERIC(20, 2),
+    ALTER COLUMN filled_amount TYPE NUMERIC(20, 8),
+    ALTER COLUMN reserved TYPE NUMERIC(20, 2);
+
+ALTER TABLE price_ticks
+    ALTER COLUMN price TYPE NUMERIC(20, 2);
+
+ALTER TABLE leaderboard_snapshots
+    ALTER COLUMN starting_money TYPE NUMERIC(20, 2),
+    ALTER COLUMN total_value TYPE NUMERIC(20, 2);
+
+ALTER TABLE fees
+    ALTER COLUMN flat_fee TYPE NUMERIC(20, 2);
+
+ALTER TABLE transactions
+    ALTER COLUMN quantity TYPE NUMERIC(20, 8),
+    ALTER COLUMN price TYPE NUMERIC(20, 2),
+    ALTER COLUMN fee TYPE NUMERIC(20, 2),
+    ALTER COLUMN cash_change TYPE NUMERIC(20, 2),
+    ALTER COLUMN balance TYPE NUMERIC(20, 2);
@@ -0,0 +1,2 @@
+-- Renamed duplicates keep their new names
+ALTER TABLE portfolios DROP CONSTRAINT portfolios_owner_name_key;
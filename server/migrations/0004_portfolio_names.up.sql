@@ -0,0 +1,8 @@
+-- Portfolios are addressed by id and only have to be told apart from the owner's
+-- other portfolios. Later duplicates get their id added so the names can be made unique.
+UPDATE portfolios p SET name = left(p.name, 240) || ' (' || p.id || ')'
+WHERE EXISTS (
+    SELECT 1 FROM portfolios q WHERE q.owner = p.owner AND q.name = p.name AND q.id < p.id
+);
+
+ALTER TABLE portfolios ADD CONSTRAINT portfolios_owner_name_key UNIQUE (owner, name);
@@ -0,0 +1,10 @@
+-- Public market events in the order they happened, so event stream clients can
+-- pick up where they left off. Each payload is the JSON sent to clients, kept as
+-- written. Old rows are pruned by the server.
+CREATE TABLE market_events (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL CHECK (kind IN ('price', 'listed', 'delisted')),
+    payload JSON NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX market_events_created_at ON market_events(created_at);
@@ -0,0 +1,232 @@
+use crate::auth::Role;
+use crate::validation::InvalidRequest;
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Everything a request can fail with. Each one has a status and a stable
+/// `code` that clients can match on, the message is only meant for people.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The body isn't JSON of the shape the route expects.
+    MalformedBody(String),
+    /// The body broke one or more `#[validate]` rules.
+    Invalid(InvalidRequest),
+    /// A request that can't be served as asked, and why.
+    BadRequest(&'static str),
+    NotLoggedIn,
+    InvalidSession,
+    SessionExpired,
+    InvalidCredentials,
+    /// A request from the price updating service with a bad or missing signature.
+    ServiceUnauthorized(String),
+    /// Signed in, but the account's role is below what the route needs.
+    Forbidden(Role),
+    InvalidPortfolioPassword,
+    PortfolioNotFound,
+    CryptoNotFound,
+    OrderNotFound,
+    UserNotFound,
+    FeesNotFound,
+    RouteNotFound,
+    EmailTaken,
+    NameTaken,
+    CryptoInUse,
+    LastAdmin,
+    OrderNotOpen,
+    NotEnoughMoney,
+    NotEnoughCrypto,
+    InvalidOrder(&'static str),
+    /// Anything the client can't do anything about. What went wrong is logged,
+    /// the client only gets told that something did.
+    Internal(String),
+}
+
+/// The body of every error response.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    error: Body<'a>,
+}
+
+#[derive(Serialize)]
+struct Body<'a> {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<&'a BTreeMap<String, Vec<String>>>,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::MalformedBody(_) => "malformed_body",
+            ApiError::Invalid(_) => "validation_failed",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotLoggedIn => "not_logged_in",
+            ApiError::InvalidSession => "invalid_session",
+            ApiError::SessionExpired => "session_expired",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::ServiceUnauthorized(_) => "service_unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::InvalidPortfolioPassword => "invalid_portfolio_password",
+            ApiError::PortfolioNotFound => "portfolio_not_found",
+            ApiError::CryptoNotFound => "crypto_not_found",
+            ApiError::OrderNotFound => "order_not_found",
+            ApiError::UserNotFound => "user_not_found",
+            ApiError::FeesNotFound => "fees_not_found",
+            ApiError::RouteNotFound => "route_not_found",
+            ApiError::EmailTaken => "email_taken",
+            ApiError::NameTaken => "name_taken",
+            ApiError::CryptoInUse => "crypto_in_use",
+            ApiError::LastAdmin => "last_admin",
+            ApiError::OrderNotOpen => "order_not_open",
+            ApiError::NotEnoughMoney => "insufficient_funds",
+            ApiError::NotEnoughCrypto => "insufficient_crypto",
+            ApiError::InvalidOrder(_) => "invalid_order",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::MalformedBody(reason) => write!(f, "Malformed request body: {}", reason),
+            ApiError::Invalid(_) => write!(f, "Invalid request"),
+            ApiError::BadRequest(reason) => write!(f, "{}", reason),
+            ApiError::NotLoggedIn => write!(f, "Not logged in"),
+            ApiError::InvalidSession => write!(f, "Invalid session"),
+            ApiError::SessionExpired => write!(f, "Session expired"),
+            ApiError::InvalidCredentials => write!(f, "Invalid email or password"),
+            ApiError::ServiceUnauthorized(reason) => write!(f, "{}", reason),
+            ApiError::Forbidden(needed) => write!(f, "Requires the {} role", needed),
+            ApiError::InvalidPortfolioPassword => write!(f, "Invalid portfolio password"),
+            ApiError::PortfolioNotFound => write!(f, "Portfolio not found"),
+            ApiError::CryptoNotFound => write!(f, "Crypto not found"),
+            ApiError::OrderNotFound => write!(f, "Order not found"),
+            ApiError::UserNotFound => write!(f, "No such user"),
+            ApiError::FeesNotFound => write!(f, "No fees set for this crypto"),
+            ApiError::RouteNotFound => write!(f, "No such route"),
+            ApiError::EmailTaken => write!(f, "Email already registered"),
+            ApiError::NameTaken => write!(f, "Name already taken"),
+            ApiError::CryptoInUse => write!(f, "Crypto is still held by portfolios"),
+            ApiError::LastAdmin => write!(f, "Can't remove the last admin"),
+            ApiError::OrderNotOpen => write!(f, "Order is not open"),
+            ApiError::NotEnoughMoney => write!(f, "Not enough money"),
+            ApiError::NotEnoughCrypto => write!(f, "Not enough crypto"),
+            ApiError::InvalidOrder(reason) => write!(f, "{}", reason),
+            ApiError::Internal(_) => write!(f, "Internal server error"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::MalformedBody(_)
+            | ApiError::Invalid(_)
+            | ApiError::BadRequest(_)
+            | ApiError::InvalidOrder(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotLoggedIn
+            | ApiError::InvalidSession
+            | ApiError::SessionExpired
+            | ApiError::InvalidCredentials
+            | ApiError::ServiceUnauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) | ApiError::InvalidPortfolioPassword => StatusCode::FORBIDDEN,
+            ApiError::PortfolioNotFound
+            | ApiError::CryptoNotFound
+            | ApiError::OrderNotFound
+            | ApiError::UserNotFound
+            | ApiError::FeesNotFound
+            | ApiError::RouteNotFound => StatusCode::NOT_FOUND,
+            ApiError::EmailTaken
+            | ApiError::NameTaken
+            | ApiError::CryptoInUse
+            | ApiError::LastAdmin
+            | ApiError::OrderNotOpen => StatusCode::CONFLICT,
+            ApiError::NotEnoughMoney | ApiError::NotEnoughCrypto => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let ApiError::Internal(details) = self {
+            eprintln!("Internal error: {}", details);
+        }
+        let fields = match self {
+            ApiError::Invalid(invalid) => Some(&invalid.fields),
+            _ => None,
+        };
+        HttpResponse::build(self.status_code()).json(Envelope {
+            error: Body {
+                code: self.code(),
+                message: self.to_string(),
+                fields,
+            },
+        })
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        ApiError::Internal(format!("DB error: {}", e))
+    }
+}
+
+impl From<InvalidRequest> for ApiError {
+    fn from(e: InvalidRequest) -> Self {
+        ApiError::Invalid(e)
+    }
+}
+
+/// Answers requests for routes that don't exist.
+pub async fn route_not_found(_req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    Err(ApiError::RouteNotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+
+    async fn respond(error: ApiError) -> (StatusCode, serde_json::Value) {
+        let response = error.error_response();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn internal_errors_keep_their_details_to_themselves() {
+        let (status, body) = respond(ApiError::Internal(
+            "DB error: relation \"users\" does not exist".to_string(),
+        ))
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["error"]["code"], "internal_error");
+        assert_eq!(body["error"]["message"], "Internal server error");
+        assert!(!body.to_string().contains("relation"));
+    }
+
+    #[actix_web::test]
+    async fn errors_have_a_code_and_a_status() {
+        let (status, body) =
+            respond(ApiError::ServiceUnauthorized("Invalid signature".into())).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["code"], "service_unauthorized");
+        assert_eq!(body["error"]["message"], "Invalid signature");
+        assert!(body["error"].get("fields").is_none());
+
+        let (status, body) = respond(ApiError::Forbidden(Role::Admin)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["message"], "Requires the admin role");
+
+        let (status, body) = respond(ApiError::NotEnoughMoney).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"]["code"], "insufficient_funds");
+    }
+}
@@ -0,0 +1,234 @@
+use crate::apierror::ApiError;
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest, web};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// How long a session stays valid without being used. Every request pushes it back.
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long a session can last however active it is, and how long the cookie is kept.
+pub const SESSION_MAX_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Sessions seen more recently than this aren't written back, so a burst of
+/// requests costs one UPDATE.
+const SESSION_TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+/// How often expired sessions are deleted.
+const SESSION_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Why a request couldn't be tied to a session.
+#[derive(Debug)]
+pub enum AuthError {
+    MissingCookie,
+    InvalidToken,
+    Expired,
+    /// Signed in, but the account's role is below what the route needs.
+    Forbidden(Role),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingCookie => write!(f, "Missing cookie"),
+            AuthError::InvalidToken => write!(f, "Invalid cookie"),
+            AuthError::Expired => write!(f, "Session expired"),
+            AuthError::Forbidden(needed) => write!(f, "Requires the {} role", needed),
+            AuthError::Database(e) => write!(f, "DB error: {}", e),
+        }
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::MissingCookie => ApiError::NotLoggedIn,
+            AuthError::InvalidToken => ApiError::InvalidSession,
+            AuthError::Expired => ApiError::SessionExpired,
+            AuthError::Forbidden(needed) => ApiError::Forbidden(needed),
+            AuthError::Database(e) => {
+                ApiError::Internal(format!("DB error (session lookup): {}", e))
+            }
+        }
+    }
+}
+
+impl From<sqlx::Error> for AuthError {
+    fn from(e: sqlx::Error) -> Self {
+        AuthError::Database(e)
+    }
+}
+
+/// What an account is allowed to do, stored in `users.role`. Each role can do
+/// everything the ones before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Player,
+    /// Manages the coin list and can audit the ledger.
+    Moderator,
+    /// Also sets fees, adjusts balances and grants roles.
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Player => "player",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "player" => Some(Role::Player),
+            "moderator" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A logged in user, from the `auth` cookie. Taking it as a handler parameter is
+/// all a route needs to require a login.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub email: String,
+    pub token: String,
+    pub role: Role,
+}
+
+/// A moderator or admin, from the `auth_root` cookie handed out by `/api/getroot`.
+#[derive(Debug, Clone)]
+pub struct ModeratorUser {
+    pub email: String,
+}
+
+/// An admin, from the `auth_root` cookie handed out by `/api/getroot`.
+#[derive(Debug, Clone)]
+pub struct AdminUser {
+    pub email: String,
+}
+
+fn pool(req: &HttpRequest) -> PgPool {
+    req.app_data::<web::Data<PgPool>>()
+        .expect("PgPool is not registered as app data")
+        .get_ref()
+        .clone()
+}
+
+/// Looks up the owner of a token that hasn't expired yet, and their role, and
+/// marks it as used.
+async fn session_owner(pool: &PgPool, token: &str) -> Result<(String, Role), AuthError> {
+    let row = sqlx::query(
+        r#"
+        SELECT t.owner, u.role,
+            t.last_seen_at > NOW() - $2 * INTERVAL '1 second'
+                AND t.created_at > NOW() - $3 * INTERVAL '1 second' AS fresh,
+            t.last_seen_at < NOW() - $4 * INTERVAL '1 second' AS stale
+        FROM token t JOIN users u ON u.email = t.owner
+        WHERE t.token = $1
+        "#,
+    )
+    .bind(token)
+    .bind(SESSION_IDLE_TIMEOUT.as_secs_f64())
+    .bind(SESSION_MAX_LIFETIME.as_secs_f64())
+    .bind(SESSION_TOUCH_INTERVAL.as_secs_f64())
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AuthError::InvalidToken)?;
+
+    if !row.try_get::<Option<bool>, _>("fresh")?.unwrap_or(false) {
+        return Err(AuthError::Expired);
+    }
+    if row.try_get::<Option<bool>, _>("stale")?.unwrap_or(false) {
+        sqlx::query("UPDATE token SET last_seen_at = NOW() WHERE token = $1")
+            .bind(token)
+            .execute(pool)
+            .await?;
+    }
+    let role: String = row.try_get("role")?;
+    // The column has a CHECK constraint, so this only fails if the two drift apart
+    let role = Role::parse(&role).ok_or(AuthError::InvalidToken)?;
+    Ok((row.try_get("owner")?, role))
+}
+
+/// The owner of the `auth_root` session, if their role is at least `needed`.
+async fn staff_session(
+    pool: &PgPool,
+    token: Option<String>,
+    needed: Role,
+) -> Result<String, AuthError> {
+    let token = token.ok_or(AuthError::MissingCookie)?;
+    let (email, role) = session_owner(pool, &token).await?;
+    if role < needed {
+        return Err(AuthError::Forbidden(needed));
+    }
+    Ok(email)
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ApiError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = req.cookie("auth").map(|c| c.value().to_string());
+        let pool = pool(req);
+        Box::pin(async move {
+            let token = token.ok_or(AuthError::MissingCookie)?;
+            let (email, role) = session_owner(&pool, &token).await?;
+            Ok(AuthenticatedUser { email, token, role })
+        })
+    }
+}
+
+impl FromRequest for ModeratorUser {
+    type Error = ApiError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = req.cookie("auth_root").map(|c| c.value().to_string());
+        let pool = pool(req);
+        Box::pin(async move {
+            let email = staff_session(&pool, token, Role::Moderator).await?;
+            Ok(ModeratorUser { email })
+        })
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = ApiError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = req.cookie("auth_root").map(|c| c.value().to_string());
+        let pool = pool(req);
+        Box::pin(async move {
+            let email = staff_session(&pool, token, Role::Admin).await?;
+            Ok(AdminUser { email })
+        })
+    }
+}
+
+/// Background task that deletes expired sessions, so the token table doesn't grow
+/// for as long as the server runs.
+pub async fn run_session_cleanup_loop(pool: PgPool) {
+    let mut interval = tokio::time::interval(SESSION_CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match crate::database::database_check_for_outtime_tokens(&pool).await {
+            Ok(0) => {}
+            Ok(deleted) => println!("Deleted {} expired sessions", deleted),
+            Err(e) => eprintln!("Error deleting expired sessions: {}", e),
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use crate::handlersorders::{Order, OrderSide};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::{PgPool, Postgres, Row, Transaction};
+use std::collections::BTreeSet;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// How many events a subscriber can fall behind before it starts missing them.
+pub const EVENT_BUFFER: usize = 1024;
+/// The NOTIFY channel every server instance publishes events on and listens to.
+const EVENT_CHANNEL: &str = "events";
+/// How long to wait before trying to listen again after the connection failed.
+const LISTENER_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// How long market events are kept for clients resuming an event stream.
+const MARKET_EVENT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often market events older than that are deleted.
+const MARKET_EVENT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Something that happened which connected clients may want pushed to them.
+/// Published only once the change is committed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Price {
+        crypto: String,
+        price: Decimal,
+        at: DateTime<Utc>,
+    },
+    /// A coin was added by a moderator, at its starting price.
+    Listed {
+        crypto: String,
+        price: Decimal,
+        at: DateTime<Utc>,
+    },
+    Delisted {
+        crypto: String,
+        at: DateTime<Utc>,
+    },
+    /// A market buy or sell went through.
+    Trade {
+        #[serde(skip)]
+        owner: String,
+        portfolio_id: i32,
+        crypto: String,
+        side: OrderSide,
+        amount: Decimal,
+        price: Decimal,
+        fee: Decimal,
+        /// What the portfolio paid, negative, or got.
+        cash_change: Decimal,
+    },
+    /// An open order was filled by the matching loop.
+    Fill {
+        #[serde(skip)]
+        owner: String,
+        portfolio_id: i32,
+        order: Order,
+    },
+    /// An order was placed, cancelled or rejected.
+    Order {
+        #[serde(skip)]
+        owner: String,
+        portfolio_id: i32,
+        order: Order,
+    },
+}
+
+impl Event {
+    /// The `type` it is sent with.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Price { .. } => "price",
+            Event::Listed { .. } => "listed",
+            Event::Delisted { .. } => "delisted",
+            Event::Trade { .. } => "trade",
+            Event::Fill { .. } => "fill",
+            Event::Order { .. } => "order",
+        }
+    }
+
+    /// The player the event is private to, if it isn't public.
+    pub fn owner(&self) -> Option<&str> {
+        match self {
+            Event::Price { .. } | Event::Listed { .. } | Event::Delisted { .. } => None,
+            Event::Trade { owner, .. } | Event::Fill { owner, .. } | Event::Order { owner, .. } => {
+                Some(owner)
+            }
+        }
+    }
+
+    fn set_owner(&mut self, to: String) {
+        match self {
+            Event::Price { .. } | Event::Listed { .. } | Event::Delisted { .. } => {}
+            Event::Trade { owner, .. } | Event::Fill { owner, .. } | Event::Order { owner, .. } => {
+                *owner = to
+            }
+        }
+    }
+}
+
+/// An event on its way to subscribers. Market events carry the id they were
+/// recorded under in `market_events`.
+#[derive(Clone, Debug)]
+pub struct Published {
+    pub id: Option<i64>,
+    pub event: Event,
+}
+
+/// What is sent between server instances. The owner goes alongside the event
+/// because clients are never sent it.
+#[derive(Serialize, Deserialize)]
+struct Notification {
+    id: Option<i64>,
+    owner: Option<String>,
+    event: Event,
+}
+
+impl Notification {
+    fn payload(published: Published) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&Notification {
+            id: published.id,
+            owner: published.event.owner().map(str::to_string),
+            event: published.event,
+        })
+    }
+
+    fn into_published(self) -> Published {
+        let mut event = self.event;
+        if let Some(owner) = self.owner {
+            event.set_owner(owner);
+        }
+        Published { id: self.id, event }
+    }
+}
+
+fn serialize_error(e: serde_json::Error) -> sqlx::Error {
+    sqlx::Error::Protocol(format!("Can't serialize event: {}", e))
+}
+
+/// Records a public market event as part of `tx`, so event stream clients can
+/// replay it. Every server instance is notified once `tx` commits.
+pub async fn record(tx: &mut Transaction<'_, Postgres>, event: Event) -> Result<(), sqlx::Error> {
+    let payload = serde_json::to_string(&event).map_err(serialize_error)?;
+    let id: i64 =
+        sqlx::query("INSERT INTO market_events (kind, payload) VALUES ($1, $2::json) RETURNING id")
+            .bind(event.kind())
+            .bind(payload)
+            .fetch_one(&mut **tx)
+            .await?
+            .try_get("id")?;
+
+    let notification = Notification::payload(Published {
+        id: Some(id),
+        event,
+    })
+    .map_err(serialize_error)?;
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(EVENT_CHANNEL)
+        .bind(notification)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// What one connected client gets sent: prices of the coins it asked for, coins
+/// being listed and delisted, and everything about its owner's own portfolios.
+#[derive(Debug)]
+pub struct Subscription {
+    owner: String,
+    pub cryptos: BTreeSet<String>,
+}
+
+impl Subscription {
+    pub fn new(owner: String) -> Self {
+        Subscription {
+            owner,
+            cryptos: BTreeSet::new(),
+        }
+    }
+
+    pub fn wants(&self, event: &Event) -> bool {
+        match event {
+            Event::Price { crypto, .. } => self.cryptos.contains(crypto),
+            Event::Listed { .. } | Event::Delisted { .. } => true,
+            _ => event.owner() == Some(self.owner.as_str()),
+        }
+    }
+}
+
+/// Fans events out to the clients connected to every server instance. Events go
+/// through Postgres, so each instance hears about the others' and its own in the
+/// same order. Slow subscribers don't hold up publishers, they lose the oldest
+/// events instead and are told how many.
+pub struct EventBus {
+    pool: PgPool,
+    sender: broadcast::Sender<Published>,
+}
+
+impl EventBus {
+    pub fn new(pool: PgPool, capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        EventBus { pool, sender }
+    }
+
+    /// Publishes an event that isn't recorded, only clients connected now get it.
+    /// The change it describes is already committed, so failing to tell anyone
+    /// is only logged.
+    pub async fn publish(&self, event: Event) {
+        let kind = event.kind();
+        let notification = match Notification::payload(Published { id: None, event }) {
+            Ok(notification) => notification,
+            Err(e) => {
+                eprintln!("Error serializing {} event: {}", kind, e);
+                return;
+            }
+        };
+        if let Err(e) = sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(EVENT_CHANNEL)
+            .bind(notification)
+            .execute(&self.pool)
+            .await
+        {
+            eprintln!("Error publishing {} event: {}", kind, e);
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Published> {
+        self.sender.subscribe()
+    }
+
+    fn deliver(&self, published: Published) {
+        // Nobody listening isn't an error
+        let _ = self.sender.send(published);
+    }
+}
+
+/// Background task that hands the events every server instance publishes to
+/// this one's subscribers.
+pub async fn run_event_listener(pool: PgPool, bus: actix_web::web::Data<EventBus>) {
+    loop {
+        if let Err(e) = listen(&pool, &bus).await {
+            eprintln!("Error listening for events: {}", e);
+        }
+        tokio::time::sleep(LISTENER_RETRY_INTERVAL).await;
+    }
+}
+
+async fn listen(pool: &PgPool, bus: &EventBus) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(EVENT_CHANNEL).await?;
+    loop {
+        // The listener reconnects by itself, but can't get back what was sent meanwhile
+        let Some(notification) = listener.try_recv().await? else {
+            eprintln!("Lost the connection listening for events, some may have been missed");
+            continue;
+        };
+        match serde_json::from_str::<Notification>(notification.payload()) {
+            Ok(notification) => bus.deliver(notification.into_published()),
+            Err(e) => eprintln!("Ignoring an event that can't be read: {}", e),
+        }
+    }
+}
+
+/// Background task that deletes market events too old to be worth replaying.
+pub async fn run_market_event_cleanup_loop(pool: PgPool) {
+    let mut interval = tokio::time::interval(MARKET_EVENT_CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let deleted = sqlx::query(
+            "DELETE FROM market_events WHERE created_at < NOW() - $1 * INTERVAL '1 second'",
+        )
+        .bind(MARKET_EVENT_RETENTION.as_secs_f64())
+        .execute(&pool)
+        .await;
+        match deleted {
+            Ok(done) if done.rows_affected() > 0 => {
+                println!("Deleted {} old market events", done.rows_affected())
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Error deleting old market events: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdb::test_pool;
+
+    fn price(crypto: &str) -> Event {
+        Event::Price {
+            crypto: crypto.to_string(),
+            price: Decimal::new(2761, 2),
+            at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn subscribers_only_get_prices_of_their_coins() {
+        let mut subscription = Subscription::new("dave@x.com".to_string());
+        assert!(!subscription.wants(&price("BTC")));
+
+        subscription.cryptos.insert("BTC".to_string());
+        assert!(subscription.wants(&price("BTC")));
+        assert!(!subscription.wants(&price("ETH")));
+    }
+
+    #[tokio::test]
+    async fn events_reach_subscribers_of_other_instances() {
+        let (Some(first), Some(second)) = (test_pool().await, test_pool().await) else {
+            return;
+        };
+        let publisher = EventBus::new(first, EVENT_BUFFER);
+        let listener = actix_web::web::Data::new(EventBus::new(second.clone(), EVENT_BUFFER));
+        let mut events = listener.subscribe();
+        let task = tokio::spawn(run_event_listener(second, listener.clone()));
+
+        let owner = uuid::Uuid::new_v4().to_string();
+        let trade = Event::Trade {
+            owner: owner.clone(),
+            portfolio_id: 1,
+            crypto: "BTC".to_string(),
+            side: OrderSide::Sell,
+            amount: Decimal::ONE,
+            price: Decimal::new(2761, 2),
+            fee: Decimal::ZERO,
+            cash_change: Decimal::new(2761, 2),
+        };
+        // Nothing is heard before the other instance starts listening, so keep trying
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                publisher.publish(trade.clone()).await;
+                let wait = tokio::time::timeout(Duration::from_millis(200), events.recv());
+                if let Ok(Ok(published)) = wait.await
+                    && published.event.owner() == Some(owner.as_str())
+                {
+                    return published;
+                }
+            }
+        })
+        .await
+        .expect("The event never arrived");
+        task.abort();
+
+        assert_eq!(received.id, None);
+        let subscription = Subscription::new(owner);
+        assert!(subscription.wants(&received.event));
+    }
+}
@@ -1,582 +1,428 @@
+use crate::apierror::ApiError;
+use crate::auth::{AuthenticatedUser, ModeratorUser, Role, SESSION_MAX_LIFETIME};
+use crate::config::Config;
+use crate::events::{self, Event};
+use crate::money;
+use crate::passwords::{PasswordMatch, hash_password, verify_password};
+use crate::serviceauth::ServiceKeys;
+use crate::validation::{self, Valid};
 use actix_web::cookie::{Cookie, SameSite};
-use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::{DateTime, Utc};
 use cookie::time;
 use rand::Rng;
-use serde::Deserialize;
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use sqlx::Row;
-use std::path::Path;
-use tokio::fs;
-use tokio::io::AsyncWriteExt; // at the top of your file
 use uuid::Uuid;
-#[derive(Deserialize)]
+use validator::Validate;
+#[derive(Deserialize, Validate)]
 pub struct RegisterDataStruct {
+    #[validate(email, length(max = 255))]
     email: String,
+    #[validate(length(min = 8, max = 128))]
     password: String,
 }
 
 pub async fn register_handler(
-    register_data: web::Json<RegisterDataStruct>,
+    register_data: Valid<RegisterDataStruct>,
     db_pool: web::Data<PgPool>,
-) -> impl Responder {
-    if register_data.password.len() < 8 {
-        return HttpResponse::BadRequest().body("Password too short");
-    }
-    if !register_data.email.contains("@") {
-        return HttpResponse::BadRequest().body("Email not correct");
-    }
-
+) -> Result<HttpResponse, ApiError> {
     // Check if email exists
-    let result = sqlx::query("SELECT 1 FROM users WHERE email = $1")
+    let existing = sqlx::query("SELECT 1 FROM users WHERE email = $1")
         .bind(&register_data.email)
         .fetch_optional(db_pool.get_ref())
-        .await;
-
-    if let Err(e) = result {
-        eprintln!("Database error (select): {}", e);
-        return HttpResponse::InternalServerError().body("Database error");
+        .await?;
+    if existing.is_some() {
+        return Err(ApiError::EmailTaken);
     }
 
-    if result.unwrap().is_some() {
-        return HttpResponse::Conflict().body("Email already registered");
-    }
+    let password_hash = hash_password(&register_data.password)
+        .await
+        .map_err(|e| ApiError::Internal(format!("Password hashing error: {}", e)))?;
 
     // Insert new user
     let insert_result = sqlx::query("INSERT INTO users (email, password) VALUES ($1, $2)")
         .bind(&register_data.email)
-        .bind(&register_data.password)
+        .bind(&password_hash)
         .execute(db_pool.get_ref())
         .await;
 
     match insert_result {
-        Ok(_) => HttpResponse::Ok().body("Register successful"),
-        Err(e) => {
-            eprintln!("Database error (insert): {:?}", e);
-            HttpResponse::InternalServerError().body("Database issue")
-        }
+        Ok(_) => Ok(HttpResponse::Ok().body("Register successful")),
+        // Someone else registered it since the check above
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err(ApiError::EmailTaken),
+        Err(e) => Err(e.into()),
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct LoginDataStruct {
+    #[validate(length(min = 1, max = 255))]
     pub email: String,
+    #[validate(length(min = 1, max = 128))]
     pub password: String,
 }
 
 pub async fn login_handler(
-    login_data: web::Json<LoginDataStruct>,
+    login_data: Valid<LoginDataStruct>,
     db_pool: web::Data<PgPool>,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     // Validate credentials
-    let user_exists = sqlx::query("SELECT 1 FROM users WHERE email = $1 AND password = $2")
+    let row = sqlx::query("SELECT password, role FROM users WHERE email = $1")
         .bind(&login_data.email)
-        .bind(&login_data.password)
         .fetch_optional(db_pool.get_ref())
-        .await;
-
-    if let Ok(Some(_)) = user_exists {
-        // Check if token already exists for user
-        let existing_token = sqlx::query("SELECT token FROM token WHERE owner = $1")
-            .bind(&login_data.email)
-            .fetch_optional(db_pool.get_ref())
-            .await;
-
-        match existing_token {
-            Ok(Some(_)) => {
-                // Token exists, check if admin
-                let is_admin = sqlx::query("SELECT 1 FROM whitelist WHERE email = $1")
-                    .bind(&login_data.email)
-                    .fetch_optional(db_pool.get_ref())
-                    .await;
-
-                let cookie_name = if is_admin.is_ok() && is_admin.unwrap().is_some() {
-                    "auth_root"
-                } else {
-                    "auth"
-                };
-
-                let token = Uuid::new_v4().to_string();
-                let cookie = Cookie::build(cookie_name, &token)
-                    .path("/")
-                    .http_only(true)
-                    .secure(false)
-                    .same_site(SameSite::Lax)
-                    .max_age(time::Duration::days(1))
-                    .finish();
-                // Insert token in DB
-                let insert_result = sqlx::query("INSERT INTO token (token, owner) VALUES ($1, $2)")
-                    .bind(&token)
-                    .bind(&login_data.email)
-                    .execute(db_pool.get_ref())
-                    .await;
-
-                if insert_result.is_err() {
-                    return HttpResponse::InternalServerError().body("Failed to insert token");
-                }
-
-                return HttpResponse::Ok()
-                    .cookie(cookie)
-                    .body(if cookie_name == "auth_root" {
-                        "Admin login successful"
-                    } else {
-                        "Login successful"
-                    });
-            }
-            Ok(None) => {
-                // No token exists, create new
-                let token = Uuid::new_v4().to_string();
-
-                // Check admin status
-                let is_admin = sqlx::query("SELECT 1 FROM whitelist WHERE email = $1")
-                    .bind(&login_data.email)
-                    .fetch_optional(db_pool.get_ref())
-                    .await;
-
-                let cookie_name = if is_admin.is_ok() && is_admin.unwrap().is_some() {
-                    "auth_root"
-                } else {
-                    "auth"
-                };
-
-                // Insert token in DB
-                let insert_result = sqlx::query("INSERT INTO token (token, owner) VALUES ($1, $2)")
-                    .bind(&token)
-                    .bind(&login_data.email)
-                    .execute(db_pool.get_ref())
-                    .await;
-
-                if insert_result.is_err() {
-                    return HttpResponse::InternalServerError().body("Failed to insert token");
-                }
-
-                let cookie = Cookie::build(cookie_name, &token)
-                    .path("/")
-                    .http_only(true)
-                    .secure(false)
-                    .same_site(SameSite::Lax)
-                    .max_age(time::Duration::days(1))
-                    .finish();
-
-                return HttpResponse::Ok()
-                    .cookie(cookie)
-                    .body(if cookie_name == "auth_root" {
-                        "Admin login successful"
-                    } else {
-                        "Login successful"
-                    });
-            }
-            Err(_) => {
-                return HttpResponse::InternalServerError().body("Database error checking token");
-            }
+        .await?
+        .ok_or(ApiError::InvalidCredentials)?;
+    let stored_password: String = row.try_get("password")?;
+    match verify_password(&login_data.password, &stored_password).await {
+        PasswordMatch::Valid => {}
+        PasswordMatch::ValidPlaintext => {
+            upgrade_user_password(db_pool.get_ref(), &login_data.email, &login_data.password).await;
         }
-    } else if let Ok(None) = user_exists {
-        HttpResponse::Unauthorized().body("Invalid email or password")
-    } else {
-        HttpResponse::InternalServerError().body("Database error during login")
+        PasswordMatch::Invalid => return Err(ApiError::InvalidCredentials),
     }
+
+    // Staff get their session in the root cookie
+    let role: String = row.try_get("role")?;
+    let cookie_name = if role == Role::Player.as_str() {
+        "auth"
+    } else {
+        "auth_root"
+    };
+
+    let token = Uuid::new_v4().to_string();
+    sqlx::query("INSERT INTO token (token, owner) VALUES ($1, $2)")
+        .bind(&token)
+        .bind(&login_data.email)
+        .execute(db_pool.get_ref())
+        .await?;
+
+    let cookie = Cookie::build(cookie_name, &token)
+        .path("/")
+        .http_only(true)
+        .secure(false)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(
+            SESSION_MAX_LIFETIME.as_secs() as i64
+        ))
+        .finish();
+
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .body(if cookie_name == "auth_root" {
+            "Admin login successful"
+        } else {
+            "Login successful"
+        }))
 }
-pub async fn logout_handler(req: HttpRequest, db_pool: web::Data<PgPool>) -> impl Responder {
-    // Try to get the "auth" cookie from the request
-    println!("{:?}", req);
-    if let Some(cookie) = req.cookie("auth") {
-        let token_value = cookie.value();
-
-        // Delete the token row from DB by token value
-        let result = sqlx::query("DELETE FROM token WHERE token = $1")
-            .bind(token_value)
-            .execute(db_pool.get_ref())
-            .await;
-
-        match result {
-            Ok(_) => {
-                // Remove cookie on client side by setting a cookie with max_age = 0
-                let expired_cookie = Cookie::build("auth", "")
-                    .path("/")
-                    .http_only(true)
-                    .max_age(time::Duration::seconds(0))
-                    .finish();
-
-                HttpResponse::Ok().cookie(expired_cookie).body("Logged out")
-            }
-            Err(e) => {
-                eprintln!("DB error on logout: {}", e);
-                HttpResponse::InternalServerError().body("Database error")
-            }
-        }
-    } else if let Some(cookie) = req.cookie("auth_root") {
-        let token_value = cookie.value();
-
-        // Delete the token row from DB by token value
-        let result = sqlx::query("DELETE FROM token WHERE token = $1")
-            .bind(token_value)
-            .execute(db_pool.get_ref())
-            .await;
-
-        match result {
-            Ok(_) => {
-                // Remove cookie on client side by setting a cookie with max_age = 0
-                let expired_cookie = Cookie::build("auth_root", "")
-                    .path("/")
-                    .http_only(true)
-                    .max_age(time::Duration::seconds(0))
-                    .finish();
-
-                HttpResponse::Ok().cookie(expired_cookie).body("Logged out")
-            }
-            Err(e) => {
-                eprintln!("DB error on logout: {}", e);
-                HttpResponse::InternalServerError().body("Database error")
-            }
+
+/// Replaces a plaintext password from before hashing. Failing only means it's tried
+/// again on the next login.
+async fn upgrade_user_password(pool: &PgPool, email: &str, password: &str) {
+    let hash = match hash_password(password).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            eprintln!("Failed to hash password of {}: {}", email, e);
+            return;
         }
-    } else {
-        return HttpResponse::BadRequest().body("No auth cookie found");
+    };
+    if let Err(e) = sqlx::query("UPDATE users SET password = $1 WHERE email = $2")
+        .bind(hash)
+        .bind(email)
+        .execute(pool)
+        .await
+    {
+        eprintln!("Failed to upgrade password of {}: {}", email, e);
     }
 }
+pub async fn logout_handler(
+    req: HttpRequest,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ApiError> {
+    // Players have an "auth" cookie, staff an "auth_root" one
+    let cookie = req
+        .cookie("auth")
+        .or_else(|| req.cookie("auth_root"))
+        .ok_or(ApiError::NotLoggedIn)?;
+
+    // Delete the token row from DB by token value
+    sqlx::query("DELETE FROM token WHERE token = $1")
+        .bind(cookie.value())
+        .execute(db_pool.get_ref())
+        .await?;
+
+    // Remove cookie on client side by setting a cookie with max_age = 0
+    let expired_cookie = Cookie::build(cookie.name().to_string(), "")
+        .path("/")
+        .http_only(true)
+        .max_age(time::Duration::seconds(0))
+        .finish();
+
+    Ok(HttpResponse::Ok().cookie(expired_cookie).body("Logged out"))
+}
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, Validate)]
 pub struct ChangeDataStruct {
+    #[validate(length(min = 1, max = 64), custom(function = "validation::name"))]
     pub name: String,
-    pub secret_key: String,
 }
 
+/// Called by the price updating service. The body is only parsed after its
+/// signature has been checked against the raw bytes.
 pub async fn change_price_handler(
+    req: HttpRequest,
+    body: web::Bytes,
     db_pool: web::Data<PgPool>,
-    change_price_data: web::Json<ChangeDataStruct>,
-) -> impl Responder {
-    if change_price_data.secret_key != "secret_no_tell" {
-        eprintln!("Invalid token");
-        return HttpResponse::InternalServerError().body("Invalid token");
+    config: web::Data<Config>,
+    service_keys: web::Data<ServiceKeys>,
+) -> Result<HttpResponse, ApiError> {
+    if let Err(e) = service_keys.verify(db_pool.get_ref(), &req, &body).await {
+        eprintln!("Refused price update: {}", e);
+        return Err(e.into());
     }
+    let change_price_data: ChangeDataStruct =
+        serde_json::from_slice(&body).map_err(|e| ApiError::MalformedBody(e.to_string()))?;
+    validation::validate(&change_price_data)?;
     // Get current price
-    let row = match sqlx::query("SELECT price FROM crypto WHERE name = $1")
+    let row = sqlx::query("SELECT price FROM crypto WHERE name = $1")
         .bind(&change_price_data.name)
-        .fetch_one(db_pool.get_ref())
-        .await
-    {
-        Ok(row) => row,
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            return HttpResponse::InternalServerError().body("Error fetching price");
-        }
-    };
+        .fetch_optional(db_pool.get_ref())
+        .await?
+        .ok_or(ApiError::CryptoNotFound)?;
 
-    let current_price: i32 = row.get("price");
+    let current_price: Decimal = row.get("price");
 
-    // Generate new price around ±10
+    // Generate new price around ±price_volatility, in whole cents
     let mut rng = rand::thread_rng();
-    let adjustment = rng.gen_range(1..=10); // always at least 1
-    let direction = if rng.gen_bool(0.5) { 1 } else { -1 }; // up or down
-    let new_price = i32::abs((current_price as i32 + direction * adjustment) as i32);
+    let max_cents = (config.price_volatility * Decimal::ONE_HUNDRED)
+        .to_i64()
+        .unwrap_or(1);
+    let adjustment = Decimal::new(rng.gen_range(1..=max_cents), money::MONEY_SCALE); // always at least a cent
+    let moved = if rng.gen_bool(0.5) {
+        current_price.checked_add(adjustment)
+    } else {
+        current_price.checked_sub(adjustment)
+    };
+    let new_price = money::checked(moved)
+        .map_err(|_| ApiError::BadRequest("Price is too large"))?
+        .abs();
     println!("New price: {}", new_price);
 
-    // Update new price
-    let update_result = sqlx::query("UPDATE crypto SET price = $1 WHERE name = $2")
-        .bind(new_price)
-        .bind(&change_price_data.name)
-        .execute(db_pool.get_ref())
-        .await;
-
-    match update_result {
-        Ok(_) => HttpResponse::Ok().body("Price changed"),
-        Err(e) => {
-            eprintln!("Error updating price: {}", e);
-            HttpResponse::InternalServerError().body("Failed to update price")
-        }
-    }
+    // Update new price and keep it in the history
+    record_price(db_pool.get_ref(), &change_price_data.name, new_price).await?;
+    Ok(HttpResponse::Ok().body("Price changed"))
 }
 
-pub async fn create_a_root(req: HttpRequest, db_pool: web::Data<PgPool>) -> impl Responder {
-    // 1. Get the "auth" cookie
-    println!("req: {:?}", req);
-    let cookie = match req.cookie("auth") {
-        Some(c) => c,
-        None => return HttpResponse::Unauthorized().body("Missing cookie"),
-    };
-
-    let token_value = cookie.value();
+/// Sets the current price of a crypto and appends it to `price_ticks`, along with
+/// the price event.
+async fn record_price(pool: &PgPool, name: &str, price: Decimal) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
 
-    // 2. Check if token is valid and get associated email
-    let row = match sqlx::query("SELECT owner FROM token WHERE token = $1")
-        .bind(token_value)
-        .fetch_optional(db_pool.get_ref())
-        .await
-    {
-        Ok(Some(row)) => row,
-        Ok(None) => return HttpResponse::Unauthorized().body("Invalid token"),
-        Err(e) => {
-            eprintln!("DB error (token lookup): {}", e);
-            return HttpResponse::InternalServerError().body("Database error");
-        }
-    };
-
-    let email: String = row.get("owner");
+    let row = sqlx::query("UPDATE crypto SET price = $1 WHERE name = $2 RETURNING id")
+        .bind(price)
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await?;
+    let crypto_id: i32 = row.try_get("id")?;
 
-    // 3. Check if user is on the whitelist
-    let is_whitelisted = match sqlx::query("SELECT 1 FROM whitelist WHERE email = $1")
-        .bind(&email)
-        .fetch_optional(db_pool.get_ref())
-        .await
-    {
-        Ok(Some(_)) => true,
-        Ok(None) => false,
-        Err(e) => {
-            eprintln!("DB error (whitelist check): {}", e);
-            return HttpResponse::InternalServerError().body("Database error");
-        }
-    };
+    let at: DateTime<Utc> = sqlx::query(
+        "INSERT INTO price_ticks (crypto_id, price) VALUES ($1, $2) RETURNING created_at",
+    )
+    .bind(crypto_id)
+    .bind(price)
+    .fetch_one(&mut *tx)
+    .await?
+    .try_get("created_at")?;
+
+    events::record(
+        &mut tx,
+        Event::Price {
+            crypto: name.to_string(),
+            price,
+            at,
+        },
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
 
-    if !is_whitelisted {
-        return HttpResponse::Unauthorized().body("Not on whitelist");
+pub async fn create_a_root(user: AuthenticatedUser) -> Result<HttpResponse, ApiError> {
+    // 1. Only staff get a root session
+    if user.role < Role::Moderator {
+        return Err(ApiError::Forbidden(Role::Moderator));
     }
 
-    // 4. Set a new cookie "auth_root"
-    let auth_root_cookie = Cookie::build("auth_root", token_value.to_string())
+    // 2. Set a new cookie "auth_root"
+    let auth_root_cookie = Cookie::build("auth_root", user.token)
         .path("/")
         .http_only(true)
         .secure(false)
         .finish();
 
-    // 5. Return response with new cookie
-    HttpResponse::Ok()
+    // 3. Return response with new cookie
+    Ok(HttpResponse::Ok()
         .cookie(auth_root_cookie)
-        .body("Root access granted")
+        .body("Root access granted"))
 }
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct CreateCryptoStruct {
+    #[validate(length(min = 1, max = 64), custom(function = "validation::name"))]
     pub name: String,
-    pub price: i32,
+    #[validate(custom(function = "validation::price"))]
+    pub price: Decimal,
 }
 
 pub async fn create_crypto(
-    req: HttpRequest,
+    moderator: ModeratorUser,
     db_pool: web::Data<PgPool>,
-    create_crypto_data: web::Json<CreateCryptoStruct>,
-) -> impl Responder {
-    println!("req: {:?}", req);
-    // Step 1: Validate admin token
-    let cookie = match req.cookie("auth_root") {
-        Some(c) => c,
-        None => return HttpResponse::Unauthorized().body("Missing or invalid cookie"),
-    };
+    create_crypto_data: Valid<CreateCryptoStruct>,
+) -> Result<HttpResponse, ApiError> {
+    let mut tx = db_pool.begin().await?;
 
-    let token_value = cookie.value();
+    // Insert new crypto, with its starting price as the first tick
+    let result = sqlx::query(
+        "WITH created AS (INSERT INTO crypto (name, creator, price) VALUES ($1, $2, $3) RETURNING id, price) INSERT INTO price_ticks (crypto_id, price) SELECT id, price FROM created RETURNING created_at",
+    )
+    .bind(&create_crypto_data.name)
+    .bind(&moderator.email)
+    .bind(create_crypto_data.price)
+    .fetch_one(&mut *tx)
+    .await;
 
-    // Step 2: Lookup owner from token
-    let row = match sqlx::query("SELECT owner FROM token WHERE token = $1")
-        .bind(token_value)
-        .fetch_optional(db_pool.get_ref())
-        .await
-    {
-        Ok(Some(row)) => row,
-        Ok(None) => return HttpResponse::Unauthorized().body("Invalid token"),
-        Err(e) => {
-            eprintln!("DB error (token lookup): {}", e);
-            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    let at: DateTime<Utc> = match result {
+        Ok(row) => row.try_get("created_at")?,
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Err(ApiError::NameTaken);
         }
+        Err(e) => return Err(e.into()),
     };
-
-    let owner: String = row.get("owner");
-
-    // Step 3: Insert new crypto
-    let result = sqlx::query("INSERT INTO crypto (name, creator, price) VALUES ($1, $2, $3)")
-        .bind(&create_crypto_data.name)
-        .bind(&owner)
-        .bind(create_crypto_data.price)
-        .execute(db_pool.get_ref())
-        .await;
-
-    match result {
-        Ok(_) => HttpResponse::Created().body("Crypto created!"),
-        Err(e) => {
-            eprintln!("DB error (insert crypto): {}", e);
-            HttpResponse::InternalServerError().body("Error inserting crypto")
-        }
-    }
+    events::record(
+        &mut tx,
+        Event::Listed {
+            crypto: create_crypto_data.name.clone(),
+            price: create_crypto_data.price,
+            at,
+        },
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(HttpResponse::Created().body("Crypto created!"))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct RemoveCryptoStruct {
+    #[validate(length(min = 1, max = 64))]
     name: String,
 }
 
 pub async fn removecrypto(
-    req: HttpRequest,
+    _moderator: ModeratorUser,
     db_pool: web::Data<PgPool>,
-    remove_crypto_data: web::Json<RemoveCryptoStruct>,
-) -> impl Responder {
-    println!("req: {:?}", req);
-    let cookie = match req.cookie("auth_root") {
-        Some(c) => c,
-        None => return HttpResponse::Unauthorized().body("Missing cookie"),
-    };
+    remove_crypto_data: Valid<RemoveCryptoStruct>,
+) -> Result<HttpResponse, ApiError> {
+    let mut tx = db_pool.begin().await?;
 
-    let token_value = cookie.value();
-    let row = match sqlx::query("SELECT owner FROM token WHERE token = $1")
-        .bind(token_value)
-        .fetch_optional(db_pool.get_ref())
-        .await
-    {
-        Ok(Some(row)) => row,
-        Ok(None) => return HttpResponse::Unauthorized().body("Invalid token"),
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
-        }
-    };
-
-    let _owner: String = row.get("owner");
-
-    let result = sqlx::query("DELETE FROM crypto WHERE name = $1")
+    let result = sqlx::query("DELETE FROM crypto WHERE name = $1 RETURNING NOW() AS at")
         .bind(remove_crypto_data.name.clone())
-        .execute(db_pool.get_ref())
+        .fetch_optional(&mut *tx)
         .await;
-    match result {
-        Ok(_) => HttpResponse::Ok().body("Deleted crypto!"),
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+    let at: DateTime<Utc> = match result {
+        Ok(Some(row)) => row.try_get("at")?,
+        Ok(None) => return Err(ApiError::CryptoNotFound),
+        Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => {
+            return Err(ApiError::CryptoInUse);
         }
-    }
+        Err(e) => return Err(e.into()),
+    };
+    events::record(
+        &mut tx,
+        Event::Delisted {
+            crypto: remove_crypto_data.name.clone(),
+            at,
+        },
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(HttpResponse::Ok().body("Deleted crypto!"))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct AddPortfolioStruct {
-    password: String, // Will hash later
+    #[validate(length(min = 8, max = 128))]
+    password: String,
+    #[validate(length(min = 1, max = 64), custom(function = "validation::name"))]
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct AddedPortfolio {
+    id: i32,
     name: String,
 }
 pub async fn addportfolio(
-    req: HttpRequest,
+    user: AuthenticatedUser,
     db_pool: web::Data<PgPool>,
-    add_portfolio_data: web::Json<AddPortfolioStruct>,
-) -> impl Responder {
-    if add_portfolio_data.password.len() < 8 {
-        return HttpResponse::BadRequest().body("Password too short");
-    }
-    println!("req: {:?}", req);
-    let cookie = match req.cookie("auth") {
-        Some(c) => c,
-        None => return HttpResponse::Unauthorized().body("Missing cookie"),
-    };
-
-    let token_value = cookie.value();
-    let row = match sqlx::query("SELECT OWNER FROM token WHERE token = $1")
-        .bind(token_value)
-        .fetch_optional(db_pool.get_ref())
+    config: web::Data<Config>,
+    add_portfolio_data: Valid<AddPortfolioStruct>,
+) -> Result<HttpResponse, ApiError> {
+    let password_hash = hash_password(&add_portfolio_data.password)
         .await
-    {
-        Ok(Some(row)) => row,
-        Ok(None) => return HttpResponse::Unauthorized().body("Invalid token"),
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
-        }
-    };
-    let _ = create_empty_portfolio_json_files(&db_pool).await;
-    let owner: String = row.get("owner");
-    let basic_amount: i32 = 1000;
+        .map_err(|e| ApiError::Internal(format!("Password hashing error: {}", e)))?;
+    // The starting money is the portfolio's first ledger entry
     let result = sqlx::query(
-        "INSERT INTO portfolios (owner, money, name, assets, password) VALUES ($1, $2, $3, $4, $5)",
+        "WITH created AS (INSERT INTO portfolios (owner, money, starting_money, name, password) VALUES ($1, $2, $2, $3, $4) RETURNING id, money) INSERT INTO transactions (portfolio_id, kind, cash_change, balance, note) SELECT id, 'deposit', money, money, 'Starting money' FROM created RETURNING portfolio_id",
     )
-    .bind(&owner)
-    .bind(&basic_amount)
+    .bind(&user.email)
+    .bind(config.starting_money)
     .bind(add_portfolio_data.name.clone())
-    .bind("".to_string())
-    .bind(add_portfolio_data.password.clone())
-    .execute(db_pool.get_ref())
+    .bind(&password_hash)
+    .fetch_one(db_pool.get_ref())
     .await;
     match result {
-        Ok(_) => HttpResponse::Created().body("Added portfolio"),
-        Err(e) => {
-            eprintln!("Db error: {}", e);
-            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
-        }
+        Ok(row) => Ok(HttpResponse::Created().json(AddedPortfolio {
+            id: row.try_get("portfolio_id")?,
+            name: add_portfolio_data.name.clone(),
+        })),
+        // Names only have to be unique among the owner's own portfolios
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err(ApiError::NameTaken),
+        Err(e) => Err(e.into()),
     }
 }
 
-pub async fn create_empty_portfolio_json_files(pool: &PgPool) -> Result<(), sqlx::Error> {
-    let rows = sqlx::query("SELECT id FROM portfolios")
-        .fetch_all(pool)
-        .await?;
-
-    let dir_path = Path::new("portfolioassets");
-    fs::create_dir_all(dir_path)
-        .await
-        .expect("Failed to create directory");
-
-    for row in rows {
-        let id: i32 = row.try_get("id")?; // Use try_get with column name
-        let file_path = dir_path.join(format!("portfolio{}.json", id));
-        if !file_path.exists() {
-            let mut file = fs::File::create(&file_path)
-                .await
-                .expect("Failed to create file");
-            file.write_all(b"").await.expect("Failed to write to file");
-            println!("Created empty file: {:?}", file_path);
-        } else {
-            println!("File already exists: {:?}", file_path);
-        }
-    }
-
-    Ok(())
-}
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct DeletePortfolioStruct {
-    name: String,
+    #[validate(range(min = 1))]
+    portfolio_id: i32,
+    #[validate(length(min = 1, max = 128))]
     password: String,
 }
 pub async fn deleteportfolio(
-    req: HttpRequest,
+    user: AuthenticatedUser,
     db_pool: web::Data<PgPool>,
-    delete_portfolio_data: web::Json<DeletePortfolioStruct>,
-) -> impl Responder {
-    println!("req: {:?}", req);
-    let cookie = match req.cookie("auth") {
-        Some(c) => c,
-        None => return HttpResponse::Unauthorized().body("Missing cookie"),
-    };
-
-    let token_value = cookie.value();
-    let _row = match sqlx::query("SELECT owner FROM token WHERE token =$1")
-        .bind(token_value)
+    delete_portfolio_data: Valid<DeletePortfolioStruct>,
+) -> Result<HttpResponse, ApiError> {
+    // Someone else's portfolio is not found, the same as one that doesn't exist
+    let row = sqlx::query("SELECT password FROM portfolios WHERE id = $1 AND owner = $2")
+        .bind(delete_portfolio_data.portfolio_id)
+        .bind(&user.email)
         .fetch_optional(db_pool.get_ref())
-        .await
-    {
-        Ok(Some(row)) => row,
-        Ok(None) => return HttpResponse::Unauthorized().body("Invalid cookie"),
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
-        }
-    };
-
-    let _valid_password = match sqlx::query("SELECT password FROM portfolios WHERE name = $1")
-        .bind(&delete_portfolio_data.name)
-        .fetch_optional(db_pool.get_ref())
-        .await
+        .await?
+        .ok_or(ApiError::PortfolioNotFound)?;
+    let stored_password: String = row.try_get("password")?;
+    if verify_password(&delete_portfolio_data.password, &stored_password).await
+        == PasswordMatch::Invalid
     {
-        Ok(Some(row)) => {
-            let stored_password: String = row.try_get("password").unwrap(); // Or handle error gracefully
-
-            if stored_password != delete_portfolio_data.password {
-                return HttpResponse::BadRequest().body("Invalid password");
-            }
-        }
-        Ok(None) => return HttpResponse::Gone().body("This shudn't happen"),
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
-        }
-    };
+        return Err(ApiError::InvalidPortfolioPassword);
+    }
 
-    let result = sqlx::query("DELETE FROM portfolios WHERE name = $1")
-        .bind(&delete_portfolio_data.name)
+    sqlx::query("DELETE FROM portfolios WHERE id = $1 AND owner = $2")
+        .bind(delete_portfolio_data.portfolio_id)
+        .bind(&user.email)
         .execute(db_pool.get_ref())
-        .await;
-    match result {
-        Ok(_) => HttpResponse::Ok().body("Portfolio deleted"),
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
-        }
-    }
+        .await?;
+    Ok(HttpResponse::Ok().body("Portfolio deleted"))
 }
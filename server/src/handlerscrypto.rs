@@ -1,6 +1,15 @@
-use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use crate::apierror::ApiError;
+use crate::auth::AuthenticatedUser;
+use crate::validation::Valid;
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use sqlx::{PgPool, Row};
+use validator::Validate;
+
+/// The most candles a single request may ask for.
+const MAX_CANDLES: i64 = 1000;
 
 #[derive(Deserialize, Debug, Serialize)]
 pub struct CryptoName {
@@ -9,145 +18,177 @@ pub struct CryptoName {
 
 #[derive(Serialize, Debug)]
 pub struct CryptoPrice {
-    price: i32,
+    price: Decimal,
 }
 
-#[derive(Serialize, Deserialize, Debug)] // FIXED: Added Deserialize
+#[derive(Serialize, Deserialize, Debug, Validate)]
 pub struct FetchSpecificStock {
+    /// Part of the name, empty matches every coin.
+    #[validate(length(max = 64))]
     name: String,
 }
 
-pub async fn fetchstocknames(req: HttpRequest, db_pool: web::Data<PgPool>) -> impl Responder {
-    let cookie = match req.cookie("auth") {
-        Some(c) => c,
-        None => return HttpResponse::Unauthorized().body("Missing cookie"),
-    };
+pub async fn fetchstocknames(
+    _user: AuthenticatedUser,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ApiError> {
+    let rows = sqlx::query("SELECT name FROM crypto")
+        .fetch_all(db_pool.get_ref())
+        .await?;
 
-    let token_value = cookie.value();
+    let names: Vec<CryptoName> = rows
+        .into_iter()
+        .map(|row| CryptoName {
+            name: row.try_get("name").unwrap_or_default(),
+        })
+        .collect();
 
-    let _owner_row = match sqlx::query("SELECT owner FROM token WHERE token=$1")
-        .bind(token_value)
-        .fetch_optional(db_pool.get_ref())
-        .await
-    {
-        Ok(Some(row)) => row,
-        Ok(None) => return HttpResponse::Unauthorized().body("Invalid cookie"),
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
-        }
-    };
+    Ok(HttpResponse::Ok().json(names))
+}
 
-    let result = sqlx::query("SELECT name FROM crypto")
+pub async fn fetchstockprices(
+    _user: AuthenticatedUser,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ApiError> {
+    let rows = sqlx::query("SELECT price FROM crypto")
         .fetch_all(db_pool.get_ref())
-        .await;
-
-    match result {
-        Ok(rows) => {
-            let names: Vec<CryptoName> = rows
-                .into_iter()
-                .map(|row| CryptoName {
-                    name: row.try_get("name").unwrap_or_default(),
-                })
-                .collect();
-
-            HttpResponse::Ok().json(names)
-        }
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            HttpResponse::InternalServerError().body("Failed to fetch names")
-        }
-    }
+        .await?;
+
+    let prices: Vec<CryptoPrice> = rows
+        .into_iter()
+        .map(|row| CryptoPrice {
+            price: row.try_get("price").unwrap_or_default(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(prices))
 }
 
-pub async fn fetchstockprices(req: HttpRequest, db_pool: web::Data<PgPool>) -> impl Responder {
-    let cookie = match req.cookie("auth") {
-        Some(c) => c,
-        None => return HttpResponse::Unauthorized().body("Missing cookie"),
-    };
-
-    let token_value = cookie.value();
+pub async fn fetchstockspecific(
+    fetch_stock_specific_data: Valid<FetchSpecificStock>,
+    _user: AuthenticatedUser,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ApiError> {
+    let pattern = format!("%{}%", &fetch_stock_specific_data.name); // Match anywhere in the name
+    let rows = sqlx::query("SELECT name FROM crypto WHERE name LIKE $1")
+        .bind(pattern)
+        .fetch_all(db_pool.get_ref())
+        .await?;
+
+    let names: Vec<CryptoName> = rows
+        .into_iter()
+        .map(|row| CryptoName {
+            name: row.try_get("name").unwrap_or_default(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(names))
+}
 
-    let _owner_row = match sqlx::query("SELECT owner FROM token WHERE token=$1")
-        .bind(token_value)
-        .fetch_optional(db_pool.get_ref())
-        .await
-    {
-        Ok(Some(row)) => row,
-        Ok(None) => return HttpResponse::Unauthorized().body("Invalid cookie"),
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
-        }
-    };
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum CandleResolution {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
 
-    let result = sqlx::query("SELECT price FROM crypto")
-        .fetch_all(db_pool.get_ref())
-        .await;
-
-    match result {
-        Ok(rows) => {
-            let prices: Vec<CryptoPrice> = rows
-                .into_iter()
-                .map(|row| CryptoPrice {
-                    price: row.try_get("price").unwrap_or_default(),
-                })
-                .collect();
-            println!("{:?}", prices);
-            HttpResponse::Ok().json(prices)
-        }
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            HttpResponse::InternalServerError().body("Failed to fetch prices")
+impl CandleResolution {
+    fn seconds(&self) -> i64 {
+        match self {
+            CandleResolution::OneMinute => 60,
+            CandleResolution::FiveMinutes => 5 * 60,
+            CandleResolution::OneHour => 60 * 60,
+            CandleResolution::OneDay => 24 * 60 * 60,
         }
     }
 }
 
-pub async fn fetchstockspecific(
-    fetch_stock_specific_data: web::Json<FetchSpecificStock>,
-    req: HttpRequest,
-    db_pool: web::Data<PgPool>,
-) -> impl Responder {
-    let cookie = match req.cookie("auth") {
-        Some(c) => c,
-        None => return HttpResponse::Unauthorized().body("Missing cookie"),
-    };
-
-    let token_value = cookie.value();
+#[derive(Deserialize, Debug, Validate)]
+pub struct FetchCandles {
+    #[validate(length(min = 1, max = 64))]
+    name: String,
+    resolution: CandleResolution,
+    /// Defaults to 100 candles before `to`.
+    from: Option<DateTime<Utc>>,
+    /// Defaults to now.
+    to: Option<DateTime<Utc>>,
+}
 
-    let _owner_row = match sqlx::query("SELECT owner FROM token WHERE token=$1")
-        .bind(token_value)
-        .fetch_optional(db_pool.get_ref())
-        .await
-    {
-        Ok(Some(row)) => row,
-        Ok(None) => return HttpResponse::Unauthorized().body("Invalid cookie"),
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
-        }
-    };
+#[derive(Serialize, Debug)]
+pub struct Candle {
+    time: DateTime<Utc>,
+    open: Decimal,
+    high: Decimal,
+    low: Decimal,
+    close: Decimal,
+    ticks: i64,
+}
 
-    let pattern = format!("%{}%", &fetch_stock_specific_data.name); // Match anywhere in the name
-    let result = sqlx::query("SELECT name FROM crypto WHERE name LIKE $1")
-        .bind(pattern)
-        .fetch_all(db_pool.get_ref())
-        .await;
-
-    match result {
-        Ok(rows) => {
-            let names: Vec<CryptoName> = rows
-                .into_iter()
-                .map(|row| CryptoName {
-                    name: row.try_get("name").unwrap_or_default(),
-                })
-                .collect();
-            println!("{:?}", names);
-            HttpResponse::Ok().json(names)
-        }
-        Err(e) => {
-            eprintln!("DB error: {}", e);
-            HttpResponse::InternalServerError().body("Failed to fetch specific data")
-        }
+/// Returns OHLC candles built from `price_ticks`. Buckets without any ticks are left out.
+pub async fn fetchcandles(
+    fetch_candles_data: Valid<FetchCandles>,
+    _user: AuthenticatedUser,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ApiError> {
+    let bucket_seconds = fetch_candles_data.resolution.seconds();
+    let to = fetch_candles_data.to.unwrap_or_else(Utc::now);
+    let from = fetch_candles_data
+        .from
+        .unwrap_or(to - Duration::seconds(bucket_seconds * 100));
+
+    if from >= to {
+        return Err(ApiError::BadRequest("from must be before to"));
+    }
+    if (to - from).num_seconds() / bucket_seconds > MAX_CANDLES {
+        return Err(ApiError::BadRequest(
+            "Time range too long for this resolution",
+        ));
     }
+
+    // A coin without ticks in the range gets no candles, one that doesn't exist an error
+    let crypto_id: i32 = sqlx::query("SELECT id FROM crypto WHERE name = $1")
+        .bind(&fetch_candles_data.name)
+        .fetch_optional(db_pool.get_ref())
+        .await?
+        .ok_or(ApiError::CryptoNotFound)?
+        .try_get("id")?;
+
+    let rows = sqlx::query(
+        r#"
+        SELECT date_bin($1 * INTERVAL '1 second', t.created_at, TIMESTAMPTZ '2000-01-01') AS time,
+            (array_agg(t.price ORDER BY t.created_at, t.id))[1] AS open,
+            MAX(t.price) AS high,
+            MIN(t.price) AS low,
+            (array_agg(t.price ORDER BY t.created_at DESC, t.id DESC))[1] AS close,
+            COUNT(*) AS ticks
+        FROM price_ticks t
+        WHERE t.crypto_id = $2 AND t.created_at >= $3 AND t.created_at < $4
+        GROUP BY 1
+        ORDER BY 1
+        "#,
+    )
+    .bind(bucket_seconds as f64)
+    .bind(crypto_id)
+    .bind(from)
+    .bind(to)
+    .fetch_all(db_pool.get_ref())
+    .await?;
+
+    let candles = rows
+        .into_iter()
+        .map(|row| {
+            Ok(Candle {
+                time: row.try_get("time")?,
+                open: row.try_get("open")?,
+                high: row.try_get("high")?,
+                low: row.try_get("low")?,
+                close: row.try_get("close")?,
+                ticks: row.try_get("ticks")?,
+            })
+        })
+        .collect::<Result<Vec<Candle>, sqlx::Error>>()?;
+    Ok(HttpResponse::Ok().json(candles))
 }
@@ -1,164 +1,697 @@
-use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use crate::apierror::ApiError;
+use crate::auth::AuthenticatedUser;
+use crate::events::{Event, EventBus};
+use crate::handlersfees::fee_schedule;
+use crate::handlersorders::OrderSide;
+use crate::handlersportfolio::{LedgerEntry, TransactionKind, record_transaction};
+use crate::money::{self, Overflow};
+use crate::passwords::{PasswordMatch, hash_password, verify_password};
+use crate::validation::{self, Valid};
+use actix_web::{HttpResponse, web};
+use rust_decimal::Decimal;
 use serde::Deserialize;
-use sqlx::{PgPool, Row};
+use sqlx::{PgPool, Postgres, Row, Transaction};
+use validator::Validate;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Validate)]
 pub struct BuyCryptoData {
-    portfolioname: String,
+    #[validate(range(min = 1))]
+    portfolio_id: i32,
+    #[validate(length(min = 1, max = 128))]
     portfoliopassword: String,
+    #[validate(length(min = 1, max = 64))]
     crypto_to_buy: String,
-    amount: i32,
+    #[validate(custom(function = "validation::quantity"))]
+    amount: Decimal,
 }
 use serde::Serialize;
 
-#[derive(Serialize, Deserialize)]
-struct CryptoPurchase {
-    name: String,
-    amount: i32,
-    price_bought: i32,
+/// Why a buy, sell or order was refused.
+#[derive(Debug)]
+pub enum TradeError {
+    PortfolioNotFound,
+    InvalidPassword,
+    CryptoNotFound,
+    NotEnoughMoney,
+    NotEnoughCrypto,
+    OrderNotFound,
+    OrderNotOpen,
+    InvalidOrder(&'static str),
+    Database(sqlx::Error),
 }
 
-pub async fn buycrypto(
-    data: web::Json<BuyCryptoData>,
-    db_pool: web::Data<PgPool>,
-    req: HttpRequest,
-) -> impl Responder {
-    let cookie = match req.cookie("auth") {
-        Some(c) => c,
-        None => return HttpResponse::Unauthorized().body("Missing cookie"),
-    };
-    let token_value = cookie.value();
-
-    // Validate token
-    let _owner = match sqlx::query("SELECT owner FROM token WHERE token = $1")
-        .bind(token_value)
-        .fetch_optional(db_pool.get_ref())
-        .await
-    {
-        Ok(Some(row)) => row.try_get::<String, _>("owner").unwrap_or_default(),
-        Ok(None) => return HttpResponse::Unauthorized().body("Invalid cookie"),
-        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
-    };
-
-    // Validate portfolio password
-    let portfolio_row = sqlx::query("SELECT password, money FROM portfolios WHERE name = $1")
-        .bind(&data.portfolioname)
-        .fetch_optional(db_pool.get_ref())
-        .await;
+impl From<sqlx::Error> for TradeError {
+    fn from(e: sqlx::Error) -> Self {
+        TradeError::Database(e)
+    }
+}
 
-    let (stored_password, mut money): (String, i32) = match portfolio_row {
-        Ok(Some(row)) => (
-            row.try_get("password").unwrap_or_default(),
-            row.try_get("money").unwrap_or_default(),
-        ),
-        Ok(None) => return HttpResponse::BadRequest().body("Portfolio not found"),
-        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
-    };
-
-    if stored_password != data.portfoliopassword {
-        return HttpResponse::BadRequest().body("Invalid portfolio password");
+impl From<Overflow> for TradeError {
+    fn from(_: Overflow) -> Self {
+        TradeError::InvalidOrder("Amount is too large")
     }
+}
 
-    // Get crypto price
-    let price_row = sqlx::query("SELECT price FROM crypto WHERE name = $1")
-        .bind(&data.crypto_to_buy)
-        .fetch_optional(db_pool.get_ref())
-        .await;
+impl From<TradeError> for ApiError {
+    fn from(e: TradeError) -> Self {
+        match e {
+            TradeError::PortfolioNotFound => ApiError::PortfolioNotFound,
+            TradeError::InvalidPassword => ApiError::InvalidPortfolioPassword,
+            TradeError::CryptoNotFound => ApiError::CryptoNotFound,
+            TradeError::NotEnoughMoney => ApiError::NotEnoughMoney,
+            TradeError::NotEnoughCrypto => ApiError::NotEnoughCrypto,
+            TradeError::OrderNotFound => ApiError::OrderNotFound,
+            TradeError::OrderNotOpen => ApiError::OrderNotOpen,
+            TradeError::InvalidOrder(reason) => ApiError::InvalidOrder(reason),
+            TradeError::Database(e) => e.into(),
+        }
+    }
+}
+
+pub struct LockedPortfolio {
+    pub id: i32,
+    pub money: Decimal,
+}
 
-    let price = match price_row {
-        Ok(Some(row)) => row.try_get::<i32, _>("price").unwrap_or(0),
-        Ok(None) => return HttpResponse::BadRequest().body("Invalid crypto name"),
-        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
-    };
+pub struct LockedCrypto {
+    pub id: i32,
+    pub name: String,
+    pub price: Decimal,
+}
 
-    let total_cost = price * data.amount;
+/// Locks one of `owner`'s portfolios for the rest of the transaction and checks its
+/// password. Someone else's portfolio is not found, the same as one that doesn't exist.
+pub async fn lock_portfolio(
+    tx: &mut Transaction<'_, Postgres>,
+    owner: &str,
+    portfolio_id: i32,
+    password: &str,
+) -> Result<LockedPortfolio, TradeError> {
+    let row = sqlx::query(
+        "SELECT id, password, money FROM portfolios WHERE id = $1 AND owner = $2 FOR UPDATE",
+    )
+    .bind(portfolio_id)
+    .bind(owner)
+    .fetch_optional(&mut **tx)
+    .await?
+    .ok_or(TradeError::PortfolioNotFound)?;
 
-    if money < total_cost {
-        return HttpResponse::BadRequest().body("Not enough money");
+    let id: i32 = row.try_get("id")?;
+    let stored_password: String = row.try_get("password")?;
+    match verify_password(password, &stored_password).await {
+        PasswordMatch::Valid => {}
+        PasswordMatch::ValidPlaintext => {
+            // Portfolios from before hashing get upgraded the first time they're used
+            let hash = hash_password(password)
+                .await
+                .map_err(|_| TradeError::InvalidPassword)?;
+            sqlx::query("UPDATE portfolios SET password = $1 WHERE id = $2")
+                .bind(hash)
+                .bind(id)
+                .execute(&mut **tx)
+                .await?;
+        }
+        PasswordMatch::Invalid => return Err(TradeError::InvalidPassword),
     }
 
-    money -= total_cost;
+    Ok(LockedPortfolio {
+        id,
+        money: row.try_get("money")?,
+    })
+}
 
-    // Simulate "buying" the crypto — store the transaction and deduct money
-    let tx_result = sqlx::query("UPDATE portfolios SET money = $1 WHERE name = $2")
-        .bind(money)
-        .bind(&data.portfolioname)
-        .execute(db_pool.get_ref())
-        .await;
+/// Locks one of `owner`'s portfolios without a password, for reading it.
+pub async fn lock_owned_portfolio(
+    tx: &mut Transaction<'_, Postgres>,
+    owner: &str,
+    portfolio_id: i32,
+) -> Result<LockedPortfolio, TradeError> {
+    let row =
+        sqlx::query("SELECT id, money FROM portfolios WHERE id = $1 AND owner = $2 FOR UPDATE")
+            .bind(portfolio_id)
+            .bind(owner)
+            .fetch_optional(&mut **tx)
+            .await?
+            .ok_or(TradeError::PortfolioNotFound)?;
 
-    if let Err(e) = tx_result {
-        return HttpResponse::InternalServerError()
-            .body(format!("Failed to update portfolio: {}", e));
+    Ok(LockedPortfolio {
+        id: row.try_get("id")?,
+        money: row.try_get("money")?,
+    })
+}
+
+/// Locks the portfolio row without a password, for the server's own background tasks.
+pub async fn lock_portfolio_by_id(
+    tx: &mut Transaction<'_, Postgres>,
+    portfolio_id: i32,
+) -> Result<LockedPortfolio, TradeError> {
+    let row = sqlx::query("SELECT id, money FROM portfolios WHERE id = $1 FOR UPDATE")
+        .bind(portfolio_id)
+        .fetch_optional(&mut **tx)
+        .await?
+        .ok_or(TradeError::PortfolioNotFound)?;
+
+    Ok(LockedPortfolio {
+        id: row.try_get("id")?,
+        money: row.try_get("money")?,
+    })
+}
+
+/// Locks the crypto row so the price can't move until the transaction ends.
+pub async fn lock_crypto(
+    tx: &mut Transaction<'_, Postgres>,
+    name: &str,
+) -> Result<LockedCrypto, TradeError> {
+    let row = sqlx::query("SELECT id, name, price FROM crypto WHERE name = $1 FOR UPDATE")
+        .bind(name)
+        .fetch_optional(&mut **tx)
+        .await?
+        .ok_or(TradeError::CryptoNotFound)?;
+
+    Ok(LockedCrypto {
+        id: row.try_get("id")?,
+        name: row.try_get("name")?,
+        price: row.try_get("price")?,
+    })
+}
+
+pub async fn lock_crypto_by_id(
+    tx: &mut Transaction<'_, Postgres>,
+    crypto_id: i32,
+) -> Result<LockedCrypto, TradeError> {
+    let row = sqlx::query("SELECT id, name, price FROM crypto WHERE id = $1 FOR UPDATE")
+        .bind(crypto_id)
+        .fetch_optional(&mut **tx)
+        .await?
+        .ok_or(TradeError::CryptoNotFound)?;
+
+    Ok(LockedCrypto {
+        id: row.try_get("id")?,
+        name: row.try_get("name")?,
+        price: row.try_get("price")?,
+    })
+}
+
+/// Money set aside for open limit buys and their fees, which market buys can't spend.
+pub async fn reserved_money(
+    tx: &mut Transaction<'_, Postgres>,
+    portfolio_id: i32,
+) -> Result<Decimal, sqlx::Error> {
+    sqlx::query(
+        "SELECT COALESCE(SUM(COALESCE(reserved, amount * limit_price)), 0) AS reserved FROM orders WHERE portfolio_id = $1 AND side = 'buy' AND kind = 'limit' AND status = 'open'",
+    )
+    .bind(portfolio_id)
+    .fetch_one(&mut **tx)
+    .await?
+    .try_get("reserved")
+}
+
+/// Coins set aside for open limit sells, which market sells can't touch.
+pub async fn reserved_crypto(
+    tx: &mut Transaction<'_, Postgres>,
+    portfolio_id: i32,
+    crypto_id: i32,
+) -> Result<Decimal, sqlx::Error> {
+    sqlx::query(
+        "SELECT COALESCE(SUM(amount), 0) AS reserved FROM orders WHERE portfolio_id = $1 AND crypto_id = $2 AND side = 'sell' AND kind = 'limit' AND status = 'open'",
+    )
+    .bind(portfolio_id)
+    .bind(crypto_id)
+    .fetch_one(&mut **tx)
+    .await?
+    .try_get("reserved")
+}
+
+pub async fn held_crypto(
+    tx: &mut Transaction<'_, Postgres>,
+    portfolio_id: i32,
+    crypto_id: i32,
+) -> Result<Decimal, sqlx::Error> {
+    sqlx::query(
+        "SELECT COALESCE(SUM(amount), 0) AS held FROM holdings WHERE portfolio_id = $1 AND crypto_id = $2",
+    )
+    .bind(portfolio_id)
+    .bind(crypto_id)
+    .fetch_one(&mut **tx)
+    .await?
+    .try_get("held")
+}
+
+#[derive(Debug)]
+pub struct BuyReceipt {
+    pub amount: Decimal,
+    pub price: Decimal,
+    pub fee: Decimal,
+    /// What the coins cost plus the fee.
+    pub total_cost: Decimal,
+}
+
+/// Debits the portfolio at the ask price plus fees and records the lot. Both rows
+/// must already be locked, portfolio before crypto.
+pub async fn buy_in_tx(
+    tx: &mut Transaction<'_, Postgres>,
+    portfolio: &LockedPortfolio,
+    crypto: &LockedCrypto,
+    amount: Decimal,
+) -> Result<BuyReceipt, TradeError> {
+    money::check_quantity(amount).map_err(TradeError::InvalidOrder)?;
+    let fees = fee_schedule(tx, crypto.id).await?;
+    let price = fees.ask(crypto.price)?;
+    // Fractions of a cent are rounded in the house's favour
+    let value = money::cents_up(money::checked(price.checked_mul(amount))?);
+    let fee = fees.fee(value)?;
+    let total_cost = money::checked(value.checked_add(fee))?;
+    let available = money::checked(
+        portfolio
+            .money
+            .checked_sub(reserved_money(tx, portfolio.id).await?),
+    )?;
+    if available < total_cost {
+        return Err(TradeError::NotEnoughMoney);
     }
 
-    // TODO: Add crypto to holdings table, or log transaction
-    /*
-    The json should look something like this:
-    {
-        {
-            name: Solana,
-            amount: 10,
-            price_bought: 104,
+    let money = money::checked(portfolio.money.checked_sub(total_cost))?;
+    sqlx::query("UPDATE portfolios SET money = $1 WHERE id = $2")
+        .bind(money)
+        .bind(portfolio.id)
+        .execute(&mut **tx)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO holdings (portfolio_id, crypto_id, amount, price_bought) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(portfolio.id)
+    .bind(crypto.id)
+    .bind(amount)
+    .bind(price)
+    .execute(&mut **tx)
+    .await?;
+
+    record_transaction(
+        tx,
+        &LedgerEntry {
+            portfolio_id: portfolio.id,
+            kind: TransactionKind::Buy,
+            crypto: Some((crypto.id, &crypto.name)),
+            quantity: amount,
+            price,
+            fee,
+            cash_change: -total_cost,
+            balance: money,
+            note: None,
         },
-        {
-            name: Solana,
-            amount: 10,
-            price_bought: 105,
+    )
+    .await?;
+
+    Ok(BuyReceipt {
+        amount,
+        price,
+        fee,
+        total_cost,
+    })
+}
+
+/// Debits the portfolio and records the lot in one transaction.
+pub async fn execute_buy(
+    pool: &PgPool,
+    owner: &str,
+    portfolio_id: i32,
+    portfolio_password: &str,
+    crypto_name: &str,
+    amount: Decimal,
+) -> Result<BuyReceipt, TradeError> {
+    let mut tx = pool.begin().await?;
+
+    let portfolio = lock_portfolio(&mut tx, owner, portfolio_id, portfolio_password).await?;
+    let crypto = lock_crypto(&mut tx, crypto_name).await?;
+    let receipt = buy_in_tx(&mut tx, &portfolio, &crypto, amount).await?;
+
+    tx.commit().await?;
+    Ok(receipt)
+}
+
+pub async fn buycrypto(
+    data: Valid<BuyCryptoData>,
+    db_pool: web::Data<PgPool>,
+    bus: web::Data<EventBus>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let receipt = execute_buy(
+        db_pool.get_ref(),
+        &user.email,
+        data.portfolio_id,
+        &data.portfoliopassword,
+        &data.crypto_to_buy,
+        data.amount,
+    )
+    .await?;
+    bus.publish(Event::Trade {
+        owner: user.email.clone(),
+        portfolio_id: data.portfolio_id,
+        crypto: data.crypto_to_buy.clone(),
+        side: OrderSide::Buy,
+        amount: receipt.amount,
+        price: receipt.price,
+        fee: receipt.fee,
+        cash_change: -receipt.total_cost,
+    })
+    .await;
+    Ok(HttpResponse::Ok().body(format!(
+        "Successfully bought {} of {} at {} for {} (fee {})",
+        receipt.amount, data.crypto_to_buy, receipt.price, receipt.total_cost, receipt.fee
+    )))
+}
+
+/// Removes `amount` coins from the oldest lots first and returns what they cost.
+async fn close_lots_fifo(
+    tx: &mut Transaction<'_, Postgres>,
+    portfolio_id: i32,
+    crypto_id: i32,
+    amount: Decimal,
+) -> Result<Decimal, TradeError> {
+    let lots = sqlx::query(
+        "SELECT id, amount, price_bought FROM holdings WHERE portfolio_id = $1 AND crypto_id = $2 ORDER BY created_at, id",
+    )
+    .bind(portfolio_id)
+    .bind(crypto_id)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let mut remaining = amount;
+    let mut cost_basis = Decimal::ZERO;
+    for lot in lots {
+        if remaining.is_zero() {
+            break;
         }
+        let lot_id: i32 = lot.try_get("id")?;
+        let lot_amount: Decimal = lot.try_get("amount")?;
+        let price_bought: Decimal = lot.try_get("price_bought")?;
+
+        let taken = remaining.min(lot_amount);
+        if taken == lot_amount {
+            sqlx::query("DELETE FROM holdings WHERE id = $1")
+                .bind(lot_id)
+                .execute(&mut **tx)
+                .await?;
+        } else {
+            sqlx::query("UPDATE holdings SET amount = amount - $1 WHERE id = $2")
+                .bind(taken)
+                .bind(lot_id)
+                .execute(&mut **tx)
+                .await?;
+        }
+        cost_basis = money::checked(
+            taken
+                .checked_mul(price_bought)
+                .and_then(|cost| cost.checked_add(cost_basis)),
+        )?;
+        remaining -= taken;
     }
-    */
-    use std::fs;
-    use std::io::{Read, Write};
-    use std::path::Path;
 
-    let price_bought: i32 = total_cost / data.amount;
+    Ok(money::cents_up(cost_basis))
+}
 
-    // Get portfolio ID to determine JSON file name
-    let id_row = sqlx::query("SELECT id FROM portfolios WHERE name = $1")
-        .bind(&data.portfolioname)
-        .fetch_one(db_pool.get_ref())
-        .await;
+#[derive(Deserialize, Debug, Validate)]
+pub struct SellCryptoData {
+    #[validate(range(min = 1))]
+    portfolio_id: i32,
+    #[validate(length(min = 1, max = 128))]
+    portfoliopassword: String,
+    #[validate(length(min = 1, max = 64))]
+    crypto_to_sell: String,
+    #[validate(custom(function = "validation::quantity"))]
+    amount: Decimal,
+}
 
-    let portfolio_id: i32 = match id_row {
-        Ok(row) => row.try_get("id").unwrap_or_default(),
-        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
-    };
+#[derive(Serialize, Debug)]
+pub struct SellCryptoResponse {
+    name: String,
+    amount: Decimal,
+    price_sold: Decimal,
+    /// What the coins sold for, before the fee.
+    proceeds: Decimal,
+    fee: Decimal,
+    cost_basis: Decimal,
+    realized_pnl: Decimal,
+    money: Decimal,
+}
 
-    // Path to the JSON file
-    let file_path = format!("portfolioassets/portfolio{}.json", portfolio_id);
-    let path = Path::new(&file_path);
+/// Closes lots and credits the portfolio at the bid price minus fees. Both rows
+/// must already be locked, portfolio before crypto.
+pub async fn sell_in_tx(
+    tx: &mut Transaction<'_, Postgres>,
+    portfolio: &LockedPortfolio,
+    crypto: &LockedCrypto,
+    amount: Decimal,
+) -> Result<SellCryptoResponse, TradeError> {
+    money::check_quantity(amount).map_err(TradeError::InvalidOrder)?;
+    let held = held_crypto(tx, portfolio.id, crypto.id).await?;
+    let reserved = reserved_crypto(tx, portfolio.id, crypto.id).await?;
+    if held - reserved < amount {
+        return Err(TradeError::NotEnoughCrypto);
+    }
 
-    // Read existing entries
-    let mut purchases: Vec<CryptoPurchase> = if path.exists() {
-        let mut file = fs::File::open(path).expect("Failed to open JSON file");
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)
-            .expect("Failed to read file");
+    let fees = fee_schedule(tx, crypto.id).await?;
+    let price = fees.bid(crypto.price);
+    let proceeds = money::cents_down(money::checked(price.checked_mul(amount))?);
+    let fee = fees.fee(proceeds)?;
+    let cash_change = money::checked(proceeds.checked_sub(fee))?;
+    // A flat fee can eat more than a tiny sale brings in
+    let money = money::checked(portfolio.money.checked_add(cash_change))?;
+    if money < reserved_money(tx, portfolio.id).await? {
+        return Err(TradeError::NotEnoughMoney);
+    }
 
-        if contents.trim().is_empty() {
-            Vec::new()
-        } else {
-            serde_json::from_str(&contents).unwrap_or_else(|_| Vec::new())
+    let cost_basis = close_lots_fifo(tx, portfolio.id, crypto.id, amount).await?;
+    let realized_pnl = money::checked(cash_change.checked_sub(cost_basis))?;
+
+    sqlx::query("UPDATE portfolios SET money = $1, realized_pnl = realized_pnl + $2 WHERE id = $3")
+        .bind(money)
+        .bind(realized_pnl)
+        .bind(portfolio.id)
+        .execute(&mut **tx)
+        .await?;
+
+    record_transaction(
+        tx,
+        &LedgerEntry {
+            portfolio_id: portfolio.id,
+            kind: TransactionKind::Sell,
+            crypto: Some((crypto.id, &crypto.name)),
+            quantity: amount,
+            price,
+            fee,
+            cash_change,
+            balance: money,
+            note: None,
+        },
+    )
+    .await?;
+
+    Ok(SellCryptoResponse {
+        name: crypto.name.clone(),
+        amount,
+        price_sold: price,
+        proceeds,
+        fee,
+        cost_basis,
+        realized_pnl,
+        money,
+    })
+}
+
+/// Closes lots and credits the portfolio in one transaction.
+pub async fn execute_sell(
+    pool: &PgPool,
+    owner: &str,
+    portfolio_id: i32,
+    portfolio_password: &str,
+    crypto_name: &str,
+    amount: Decimal,
+) -> Result<SellCryptoResponse, TradeError> {
+    let mut tx = pool.begin().await?;
+
+    let portfolio = lock_portfolio(&mut tx, owner, portfolio_id, portfolio_password).await?;
+    let crypto = lock_crypto(&mut tx, crypto_name).await?;
+    let response = sell_in_tx(&mut tx, &portfolio, &crypto, amount).await?;
+
+    tx.commit().await?;
+    Ok(response)
+}
+
+pub async fn sellcrypto(
+    data: Valid<SellCryptoData>,
+    db_pool: web::Data<PgPool>,
+    bus: web::Data<EventBus>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let response = execute_sell(
+        db_pool.get_ref(),
+        &user.email,
+        data.portfolio_id,
+        &data.portfoliopassword,
+        &data.crypto_to_sell,
+        data.amount,
+    )
+    .await?;
+    bus.publish(Event::Trade {
+        owner: user.email.clone(),
+        portfolio_id: data.portfolio_id,
+        crypto: response.name.clone(),
+        side: OrderSide::Sell,
+        amount: response.amount,
+        price: response.price_sold,
+        fee: response.fee,
+        cash_change: response.proceeds - response.fee,
+    })
+    .await;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdb::test_pool;
+
+    #[tokio::test]
+    async fn concurrent_buys_never_overspend() {
+        let Some(pool) = test_pool().await else {
+            return;
+        };
+        let suffix = uuid::Uuid::new_v4().to_string();
+        let portfolio_name = format!("race-{}", suffix);
+        let crypto_name = format!("racecoin-{}", suffix);
+
+        sqlx::query(
+            "INSERT INTO users (email, password) VALUES ('test', 'password') ON CONFLICT DO NOTHING",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        // No fees on this coin, so every buy costs exactly 50
+        sqlx::query(
+            "WITH created AS (INSERT INTO crypto (name, creator, price) VALUES ($1, 'test', 10) RETURNING id) INSERT INTO fees (crypto_id) SELECT id FROM created",
+        )
+        .bind(&crypto_name)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let portfolio_id: i32 = sqlx::query(
+            "INSERT INTO portfolios (owner, money, name, password) VALUES ('test', 1000, $1, 'password') RETURNING id",
+        )
+        .bind(&portfolio_name)
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .get("id");
+
+        // 50 buys of 50 money each against a balance that only covers 20 of them
+        let mut tasks = Vec::new();
+        for _ in 0..50 {
+            let pool = pool.clone();
+            let crypto_name = crypto_name.clone();
+            tasks.push(tokio::spawn(async move {
+                execute_buy(
+                    &pool,
+                    "test",
+                    portfolio_id,
+                    "password",
+                    &crypto_name,
+                    Decimal::from(5),
+                )
+                .await
+            }));
+        }
+
+        let mut filled = 0;
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok(_) => filled += 1,
+                Err(TradeError::NotEnoughMoney) => {}
+                Err(e) => panic!("unexpected error: {:?}", e),
+            }
         }
-    } else {
-        Vec::new()
-    };
-
-    // Add the new purchase
-    purchases.push(CryptoPurchase {
-        name: data.crypto_to_buy.clone(),
-        amount: data.amount,
-        price_bought,
-    });
-
-    // Write back to file
-    let json = serde_json::to_string_pretty(&purchases).expect("Failed to serialize");
-    let mut file = fs::File::create(path).expect("Failed to open file for writing");
-    file.write_all(json.as_bytes())
-        .expect("Failed to write JSON");
-
-    HttpResponse::Ok().body(format!(
-        "Successfully bought {} of {} for {}",
-        data.amount, data.crypto_to_buy, total_cost
-    ))
+
+        let row = sqlx::query(
+            "SELECT p.money, COALESCE(SUM(h.amount), 0) AS held FROM portfolios p LEFT JOIN holdings h ON h.portfolio_id = p.id WHERE p.id = $1 GROUP BY p.id",
+        )
+        .bind(portfolio_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let money: Decimal = row.get("money");
+        let held: Decimal = row.get("held");
+
+        assert!(money >= Decimal::ZERO);
+        assert_eq!(filled, 20);
+        assert_eq!(money, Decimal::ZERO);
+        assert_eq!(held, Decimal::from(100));
+
+        sqlx::query("DELETE FROM portfolios WHERE id = $1")
+            .bind(portfolio_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM crypto WHERE name = $1")
+            .bind(&crypto_name)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_players_portfolios_are_not_found() {
+        let Some(pool) = test_pool().await else {
+            return;
+        };
+        let suffix = uuid::Uuid::new_v4().to_string();
+        let owner = format!("owner-{}", suffix);
+        let intruder = format!("intruder-{}", suffix);
+        for email in [&owner, &intruder] {
+            sqlx::query("INSERT INTO users (email, password) VALUES ($1, 'password')")
+                .bind(email)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let portfolio_id: i32 = sqlx::query(
+            "INSERT INTO portfolios (owner, money, name, password) VALUES ($1, 1000, 'main', 'password') RETURNING id",
+        )
+        .bind(&owner)
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .get("id");
+
+        // Knowing the id and the password isn't enough
+        let mut tx = pool.begin().await.unwrap();
+        let result = lock_portfolio(&mut tx, &intruder, portfolio_id, "password").await;
+        assert!(matches!(result, Err(TradeError::PortfolioNotFound)));
+        let result = lock_portfolio(&mut tx, &owner, portfolio_id, "password").await;
+        assert!(result.is_ok());
+        tx.rollback().await.unwrap();
+
+        // Names are unique per owner, but two players can both have a "main"
+        let duplicate = sqlx::query(
+            "INSERT INTO portfolios (owner, money, name, password) VALUES ($1, 1000, 'main', 'password')",
+        )
+        .bind(&owner)
+        .execute(&pool)
+        .await;
+        assert!(matches!(duplicate, Err(sqlx::Error::Database(e)) if e.is_unique_violation()));
+        sqlx::query(
+            "INSERT INTO portfolios (owner, money, name, password) VALUES ($1, 1000, 'main', 'password')",
+        )
+        .bind(&intruder)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        sqlx::query("DELETE FROM users WHERE email = $1 OR email = $2")
+            .bind(&owner)
+            .bind(&intruder)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,184 @@
+use crate::apierror::ApiError;
+use crate::auth::{AdminUser, AuthenticatedUser};
+use crate::money::{self, Overflow};
+use crate::validation::{self, Valid};
+use actix_web::{HttpResponse, web};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Row, Transaction};
+use validator::Validate;
+
+/// Basis points in 100%.
+const BPS: i64 = 10_000;
+
+/// What a trade costs on top of the coins themselves. Amounts are in the same
+/// units as `crypto.price`, percentages in basis points.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct FeeSchedule {
+    pub flat_fee: Decimal,
+    /// Charged on the value of the trade.
+    pub percent_bps: i32,
+    /// Full width of the bid/ask spread around `crypto.price`. Buyers pay half of it
+    /// above the price and sellers get half of it below.
+    pub spread_bps: i32,
+}
+
+/// `bps` basis points of `value`. Can't overflow for anything that fits in a column.
+fn share(value: Decimal, bps: i64) -> Decimal {
+    value * Decimal::from(bps) / Decimal::from(BPS)
+}
+
+impl FeeSchedule {
+    fn half_spread(&self, price: Decimal) -> Decimal {
+        // Rounded up, so any spread at all moves the price
+        money::cents_up(share(price, self.spread_bps as i64) / Decimal::TWO)
+    }
+
+    /// The price a buyer pays per coin.
+    pub fn ask(&self, price: Decimal) -> Result<Decimal, Overflow> {
+        money::checked(price.checked_add(self.half_spread(price)))
+    }
+
+    /// The price a seller gets per coin.
+    pub fn bid(&self, price: Decimal) -> Decimal {
+        (price - self.half_spread(price)).max(Decimal::ZERO)
+    }
+
+    /// The fee for a trade worth `value`, rounded up.
+    pub fn fee(&self, value: Decimal) -> Result<Decimal, Overflow> {
+        let percent = money::cents_up(share(value, self.percent_bps as i64));
+        money::checked(self.flat_fee.checked_add(percent))
+    }
+}
+
+/// The schedule for a coin, falling back to the global one, or no fees at all
+/// when neither is set.
+pub async fn fee_schedule(
+    tx: &mut Transaction<'_, Postgres>,
+    crypto_id: i32,
+) -> Result<FeeSchedule, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT flat_fee, percent_bps, spread_bps FROM fees WHERE crypto_id = $1 OR crypto_id IS NULL ORDER BY crypto_id NULLS LAST LIMIT 1",
+    )
+    .bind(crypto_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    match row {
+        Some(row) => Ok(FeeSchedule {
+            flat_fee: row.try_get("flat_fee")?,
+            percent_bps: row.try_get("percent_bps")?,
+            spread_bps: row.try_get("spread_bps")?,
+        }),
+        None => Ok(FeeSchedule::default()),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConfiguredFees {
+    /// `None` for the global schedule.
+    crypto: Option<String>,
+    #[serde(flatten)]
+    schedule: FeeSchedule,
+}
+
+/// The global schedule and every coin that overrides it.
+pub async fn fetchfees(
+    _user: AuthenticatedUser,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ApiError> {
+    let rows = sqlx::query(
+        "SELECT c.name, f.flat_fee, f.percent_bps, f.spread_bps FROM fees f LEFT JOIN crypto c ON c.id = f.crypto_id ORDER BY f.crypto_id NULLS FIRST",
+    )
+    .fetch_all(db_pool.get_ref())
+    .await?;
+
+    let fees: Vec<ConfiguredFees> = rows
+        .into_iter()
+        .map(|row| ConfiguredFees {
+            crypto: row.try_get("name").unwrap_or_default(),
+            schedule: FeeSchedule {
+                flat_fee: row.try_get("flat_fee").unwrap_or_default(),
+                percent_bps: row.try_get("percent_bps").unwrap_or_default(),
+                spread_bps: row.try_get("spread_bps").unwrap_or_default(),
+            },
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(fees))
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct SetFeesData {
+    /// Leave out to set the global schedule.
+    #[validate(length(min = 1, max = 64))]
+    crypto: Option<String>,
+    #[validate(custom(function = "validation::fee"))]
+    flat_fee: Decimal,
+    #[validate(range(min = 0, max = 10_000))]
+    percent_bps: i32,
+    #[validate(range(min = 0, max = 10_000))]
+    spread_bps: i32,
+}
+
+pub async fn setfees(
+    data: Valid<SetFeesData>,
+    db_pool: web::Data<PgPool>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    let crypto_id = match &data.crypto {
+        Some(name) => {
+            let row = sqlx::query("SELECT id FROM crypto WHERE name = $1")
+                .bind(name)
+                .fetch_optional(db_pool.get_ref())
+                .await?
+                .ok_or(ApiError::CryptoNotFound)?;
+            Some(row.get::<i32, _>("id"))
+        }
+        None => None,
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO fees (crypto_id, flat_fee, percent_bps, spread_bps) VALUES ($1, $2, $3, $4)
+        ON CONFLICT ((COALESCE(crypto_id, 0))) DO UPDATE SET
+            flat_fee = EXCLUDED.flat_fee,
+            percent_bps = EXCLUDED.percent_bps,
+            spread_bps = EXCLUDED.spread_bps
+        "#,
+    )
+    .bind(crypto_id)
+    .bind(data.flat_fee)
+    .bind(data.percent_bps)
+    .bind(data.spread_bps)
+    .execute(db_pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().body(match &data.crypto {
+        Some(name) => format!("Fees for {} set", name),
+        None => "Global fees set".to_string(),
+    }))
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct RemoveFeesData {
+    #[validate(length(min = 1, max = 64))]
+    crypto: String,
+}
+
+/// Drops a coin's own schedule so it falls back to the global one.
+pub async fn removefees(
+    data: Valid<RemoveFeesData>,
+    db_pool: web::Data<PgPool>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    let done =
+        sqlx::query("DELETE FROM fees WHERE crypto_id IN (SELECT id FROM crypto WHERE name = $1)")
+            .bind(&data.crypto)
+            .execute(db_pool.get_ref())
+            .await?;
+
+    if done.rows_affected() == 0 {
+        return Err(ApiError::FeesNotFound);
+    }
+    Ok(HttpResponse::Ok().body(format!("Fees for {} removed", data.crypto)))
+}
@@ -0,0 +1,232 @@
+use crate::apierror::ApiError;
+use crate::auth::AuthenticatedUser;
+use crate::handlersportfolio::percent_of;
+use crate::validation::Valid;
+use actix_web::{HttpResponse, web};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::{PgPool, Row};
+use std::time::Duration;
+use validator::Validate;
+
+/// How often today's leaderboard snapshot is refreshed. The last refresh of a day
+/// is what that day's movement is measured against.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_PER_PAGE: i64 = 20;
+const MAX_PER_PAGE: i64 = 100;
+/// Far past the last portfolio, low enough that the offset can't overflow.
+const MAX_PAGE: i64 = 1_000_000;
+
+/// Every portfolio with its cash plus the current value of its holdings.
+const VALUED_PORTFOLIOS: &str = r#"
+    SELECT p.id, p.name, p.owner, p.starting_money,
+        ROUND(p.money + COALESCE(SUM(h.amount * c.price), 0), 2) AS total_value
+    FROM portfolios p
+    LEFT JOIN holdings h ON h.portfolio_id = p.id
+    LEFT JOIN crypto c ON c.id = h.crypto_id
+    GROUP BY p.id
+"#;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaderboardBy {
+    /// Every portfolio is ranked on its own.
+    #[default]
+    Portfolio,
+    /// Every player is ranked by their best portfolio.
+    User,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct LeaderboardData {
+    #[serde(default)]
+    by: LeaderboardBy,
+    /// Starts at 1. The top N is page 1 with `per_page` N.
+    #[validate(range(min = 1, max = MAX_PAGE))]
+    page: Option<i64>,
+    #[validate(range(min = 1))]
+    per_page: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LeaderboardEntry {
+    rank: i64,
+    /// Rank in yesterday's snapshot, if the portfolio or player was on it.
+    previous_rank: Option<i64>,
+    /// With `by` user, the player's best portfolio. Players are only known by their
+    /// portfolio names here, their emails are kept private.
+    portfolio: String,
+    total_value: Decimal,
+    total_return: Decimal,
+    total_return_percent: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Leaderboard {
+    by: LeaderboardBy,
+    page: i64,
+    per_page: i64,
+    total: i64,
+    entries: Vec<LeaderboardEntry>,
+    /// The caller's own entries, only filled in when they are logged in.
+    mine: Vec<LeaderboardEntry>,
+}
+
+/// Builds the ranking query for one board. The result has one row per entry
+/// with its current and previous rank.
+fn ranking_query(by: LeaderboardBy) -> String {
+    // Yesterday's snapshot is ranked the same way as today's board
+    let (board, previous_board, join) = match by {
+        LeaderboardBy::Portfolio => (
+            "SELECT * FROM valued",
+            "SELECT * FROM previous_valued",
+            "pr.id = r.id",
+        ),
+        LeaderboardBy::User => (
+            "SELECT DISTINCT ON (owner) * FROM valued ORDER BY owner, total_value - starting_money DESC, id",
+            "SELECT DISTINCT ON (owner) * FROM previous_valued ORDER BY owner, total_value - starting_money DESC, id",
+            "pr.owner = r.owner",
+        ),
+    };
+
+    format!(
+        r#"
+        WITH valued AS ({VALUED_PORTFOLIOS}),
+        board AS ({board}),
+        ranked AS (
+            SELECT *, total_value - starting_money AS total_return,
+                RANK() OVER (ORDER BY total_value - starting_money DESC) AS rank
+            FROM board
+        ),
+        previous_valued AS (
+            SELECT portfolio_id AS id, owner, starting_money, total_value
+            FROM leaderboard_snapshots
+            WHERE day = CURRENT_DATE - 1
+        ),
+        previous_board AS ({previous_board}),
+        previous AS (
+            SELECT id, owner, RANK() OVER (ORDER BY total_value - starting_money DESC) AS rank
+            FROM previous_board
+        )
+        SELECT r.rank, pr.rank AS previous_rank, r.owner, r.name,
+            r.total_value, r.total_return, r.starting_money, COUNT(*) OVER () AS total
+        FROM ranked r
+        LEFT JOIN previous pr ON {join}
+        ORDER BY r.rank, r.id
+        "#
+    )
+}
+
+fn entry_from_row(row: &PgRow) -> Result<LeaderboardEntry, sqlx::Error> {
+    let total_return: Decimal = row.try_get("total_return")?;
+    let starting_money: Decimal = row.try_get("starting_money")?;
+    Ok(LeaderboardEntry {
+        rank: row.try_get("rank")?,
+        previous_rank: row.try_get("previous_rank")?,
+        portfolio: row
+            .try_get::<Option<String>, _>("name")?
+            .unwrap_or_default(),
+        total_value: row.try_get("total_value")?,
+        total_return,
+        total_return_percent: percent_of(total_return, starting_money),
+    })
+}
+
+async fn leaderboard(
+    pool: &PgPool,
+    data: &LeaderboardData,
+    caller: Option<&str>,
+) -> Result<Leaderboard, sqlx::Error> {
+    let page = data.page.unwrap_or(1).max(1);
+    let per_page = data
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+
+    let rows = sqlx::query(&format!("{} LIMIT $1 OFFSET $2", ranking_query(data.by)))
+        .bind(per_page)
+        .bind((page - 1) * per_page)
+        .fetch_all(pool)
+        .await?;
+
+    let total = match rows.first() {
+        Some(row) => row.try_get("total")?,
+        None => 0,
+    };
+    let entries = rows
+        .iter()
+        .map(entry_from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Filtering outside the ranking keeps the caller's overall rank
+    let mine = match caller {
+        Some(owner) => {
+            let rows = sqlx::query(&format!(
+                "SELECT * FROM ({}) mine WHERE mine.owner = $1 ORDER BY mine.rank",
+                ranking_query(data.by)
+            ))
+            .bind(owner)
+            .fetch_all(pool)
+            .await?;
+            rows.iter()
+                .map(entry_from_row)
+                .collect::<Result<Vec<_>, _>>()?
+        }
+        None => Vec::new(),
+    };
+
+    Ok(Leaderboard {
+        by: data.by,
+        page,
+        per_page,
+        total,
+        entries,
+        mine,
+    })
+}
+
+/// Ranks portfolios, or players by their best portfolio, on their return over the
+/// starting money. Anyone can read it; logged in players also get their own rank.
+pub async fn getleaderboard(
+    data: Valid<LeaderboardData>,
+    db_pool: web::Data<PgPool>,
+    caller: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let caller = caller.map(|user| user.email);
+
+    let board = leaderboard(db_pool.get_ref(), &data, caller.as_deref()).await?;
+    Ok(HttpResponse::Ok().json(board))
+}
+
+/// Stores today's value and rank of every portfolio, replacing an earlier snapshot
+/// from the same day.
+pub async fn snapshot_leaderboard(pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        r#"
+        WITH valued AS ({VALUED_PORTFOLIOS})
+        INSERT INTO leaderboard_snapshots (day, portfolio_id, owner, starting_money, total_value, rank)
+        SELECT CURRENT_DATE, id, owner, starting_money, total_value,
+            RANK() OVER (ORDER BY total_value - starting_money DESC)
+        FROM valued
+        ON CONFLICT (day, portfolio_id) DO UPDATE SET
+            owner = EXCLUDED.owner,
+            starting_money = EXCLUDED.starting_money,
+            total_value = EXCLUDED.total_value,
+            rank = EXCLUDED.rank
+        "#
+    ))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn run_snapshot_loop(pool: PgPool) {
+    let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = snapshot_leaderboard(&pool).await {
+            eprintln!("Error taking leaderboard snapshot: {}", e);
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use crate::apierror::ApiError;
+use crate::auth::AuthenticatedUser;
+use crate::events::{Event, EventBus};
+use crate::handlerscryptoapi::{
+    TradeError, buy_in_tx, held_crypto, lock_crypto_by_id, lock_portfolio, lock_portfolio_by_id,
+    reserved_crypto, reserved_money, sell_in_tx,
+};
+use crate::handlersfees::fee_schedule;
+use crate::money;
+use crate::validation::{self, Valid};
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::{PgPool, Postgres, Row, Transaction};
+use std::time::Duration;
+use validator::Validate;
+
+/// How often open orders are checked against the current prices.
+const MATCHING_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+impl OrderSide {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OrderSide::Buy => "buy",
+            OrderSide::Sell => "sell",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderKind {
+    #[default]
+    Limit,
+    StopLoss,
+    TakeProfit,
+}
+
+impl OrderKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OrderKind::Limit => "limit",
+            OrderKind::StopLoss => "stop_loss",
+            OrderKind::TakeProfit => "take_profit",
+        }
+    }
+}
+
+/// Whether an order should fire at `price`. Limit buys and stop-losses wait for the
+/// price to fall to `limit_price`, limit sells and take-profits for it to rise to it.
+fn crosses(kind: &str, side: &str, price: Decimal, limit_price: Decimal) -> bool {
+    match (kind, side) {
+        ("limit", "buy") | ("stop_loss", _) => price <= limit_price,
+        _ => price >= limit_price,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Order {
+    id: i32,
+    crypto: String,
+    side: String,
+    kind: String,
+    amount: Decimal,
+    limit_price: Decimal,
+    status: String,
+    fill_price: Option<Decimal>,
+    filled_amount: Option<Decimal>,
+    created_at: DateTime<Utc>,
+    closed_at: Option<DateTime<Utc>>,
+}
+
+const ORDER_SELECT: &str = "SELECT o.id, c.name, o.side, o.kind, o.amount, o.limit_price, o.status, o.fill_price, o.filled_amount, o.created_at, o.closed_at FROM orders o JOIN crypto c ON c.id = o.crypto_id";
+
+fn order_from_row(row: &PgRow) -> Result<Order, sqlx::Error> {
+    Ok(Order {
+        id: row.try_get("id")?,
+        crypto: row.try_get("name")?,
+        side: row.try_get("side")?,
+        kind: row.try_get("kind")?,
+        amount: row.try_get::<Decimal, _>("amount")?.normalize(),
+        limit_price: row.try_get("limit_price")?,
+        status: row.try_get("status")?,
+        fill_price: row.try_get("fill_price")?,
+        filled_amount: row
+            .try_get::<Option<Decimal>, _>("filled_amount")?
+            .map(|amount| amount.normalize()),
+        created_at: row.try_get("created_at")?,
+        closed_at: row.try_get("closed_at")?,
+    })
+}
+
+async fn fetch_order(
+    tx: &mut Transaction<'_, Postgres>,
+    order_id: i32,
+) -> Result<Order, sqlx::Error> {
+    let row = sqlx::query(&format!("{} WHERE o.id = $1", ORDER_SELECT))
+        .bind(order_id)
+        .fetch_one(&mut **tx)
+        .await?;
+    order_from_row(&row)
+}
+
+/// A portfolio's open orders, oldest first.
+pub async fn open_orders(
+    tx: &mut Transaction<'_, Postgres>,
+    portfolio_id: i32,
+) -> Result<Vec<Order>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        "{} WHERE o.portfolio_id = $1 AND o.status = 'open' ORDER BY o.created_at, o.id",
+        ORDER_SELECT
+    ))
+    .bind(portfolio_id)
+    .fetch_all(&mut **tx)
+    .await?;
+    rows.iter().map(order_from_row).collect()
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct PlaceOrderData {
+    #[validate(range(min = 1))]
+    portfolio_id: i32,
+    #[validate(length(min = 1, max = 128))]
+    portfoliopassword: String,
+    #[validate(length(min = 1, max = 64))]
+    crypto: String,
+    side: OrderSide,
+    #[serde(default)]
+    kind: OrderKind,
+    #[validate(custom(function = "validation::quantity"))]
+    amount: Decimal,
+    /// The trigger price for stop-loss and take-profit orders.
+    #[validate(custom(function = "validation::price"))]
+    limit_price: Decimal,
+}
+
+/// Checks that the portfolio can cover the order on top of its other open orders,
+/// then stores it. Stop-loss and take-profit orders only need the holding to exist.
+async fn place_order(
+    pool: &PgPool,
+    owner: &str,
+    data: &PlaceOrderData,
+) -> Result<Order, TradeError> {
+    let mut tx = pool.begin().await?;
+
+    let portfolio =
+        lock_portfolio(&mut tx, owner, data.portfolio_id, &data.portfoliopassword).await?;
+    let crypto_id: i32 = sqlx::query("SELECT id FROM crypto WHERE name = $1")
+        .bind(&data.crypto)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(TradeError::CryptoNotFound)?
+        .try_get("id")?;
+
+    let mut reserved = None;
+    match (data.kind, data.side) {
+        (OrderKind::StopLoss | OrderKind::TakeProfit, OrderSide::Buy) => {
+            return Err(TradeError::InvalidOrder(
+                "Stop-loss and take-profit orders can only sell",
+            ));
+        }
+        (OrderKind::StopLoss | OrderKind::TakeProfit, OrderSide::Sell) => {
+            let held = held_crypto(&mut tx, portfolio.id, crypto_id).await?;
+            if held < data.amount {
+                return Err(TradeError::NotEnoughCrypto);
+            }
+        }
+        (OrderKind::Limit, OrderSide::Buy) => {
+            // The fill never costs more than the limit price, so its fee can be held back now
+            let value = money::cents_up(money::checked(data.amount.checked_mul(data.limit_price))?);
+            let fee = fee_schedule(&mut tx, crypto_id).await?.fee(value)?;
+            let cost = money::checked(value.checked_add(fee))?;
+            let available = portfolio.money - reserved_money(&mut tx, portfolio.id).await?;
+            if available < cost {
+                return Err(TradeError::NotEnoughMoney);
+            }
+            reserved = Some(cost);
+        }
+        (OrderKind::Limit, OrderSide::Sell) => {
+            let held = held_crypto(&mut tx, portfolio.id, crypto_id).await?;
+            let reserved = reserved_crypto(&mut tx, portfolio.id, crypto_id).await?;
+            if held - reserved < data.amount {
+                return Err(TradeError::NotEnoughCrypto);
+            }
+        }
+    }
+
+    let order_id: i32 = sqlx::query(
+        "INSERT INTO orders (portfolio_id, crypto_id, side, kind, amount, limit_price, reserved) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+    )
+    .bind(portfolio.id)
+    .bind(crypto_id)
+    .bind(data.side.as_str())
+    .bind(data.kind.as_str())
+    .bind(data.amount)
+    .bind(data.limit_price)
+    .bind(reserved)
+    .fetch_one(&mut *tx)
+    .await?
+    .try_get("id")?;
+
+    let order = fetch_order(&mut tx, order_id).await?;
+    tx.commit().await?;
+    Ok(order)
+}
+
+pub async fn placeorder(
+    data: Valid<PlaceOrderData>,
+    db_pool: web::Data<PgPool>,
+    bus: web::Data<EventBus>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let order = place_order(db_pool.get_ref(), &user.email, &data).await?;
+    bus.publish(Event::Order {
+        owner: user.email.clone(),
+        portfolio_id: data.portfolio_id,
+        order: order.clone(),
+    })
+    .await;
+    Ok(HttpResponse::Created().json(order))
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct ListOrdersData {
+    #[validate(range(min = 1))]
+    portfolio_id: i32,
+    #[validate(length(min = 1, max = 128))]
+    portfoliopassword: String,
+}
+
+async fn list_orders(
+    pool: &PgPool,
+    owner: &str,
+    data: &ListOrdersData,
+) -> Result<Vec<Order>, TradeError> {
+    let mut tx = pool.begin().await?;
+
+    let portfolio =
+        lock_portfolio(&mut tx, owner, data.portfolio_id, &data.portfoliopassword).await?;
+    let rows = sqlx::query(&format!(
+        "{} WHERE o.portfolio_id = $1 ORDER BY o.created_at DESC, o.id DESC",
+        ORDER_SELECT
+    ))
+    .bind(portfolio.id)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let orders = rows
+        .iter()
+        .map(order_from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    tx.commit().await?;
+    Ok(orders)
+}
+
+pub async fn listorders(
+    data: Valid<ListOrdersData>,
+    db_pool: web::Data<PgPool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let orders = list_orders(db_pool.get_ref(), &user.email, &data).await?;
+    Ok(HttpResponse::Ok().json(orders))
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct CancelOrderData {
+    #[validate(range(min = 1))]
+    portfolio_id: i32,
+    #[validate(length(min = 1, max = 128))]
+    portfoliopassword: String,
+    #[validate(range(min = 1))]
+    order_id: i32,
+}
+
+/// Closes an open order, which hands its reserved money or coins back to the portfolio.
+async fn cancel_order(
+    pool: &PgPool,
+    owner: &str,
+    data: &CancelOrderData,
+) -> Result<Order, TradeError> {
+    let mut tx = pool.begin().await?;
+
+    let portfolio =
+        lock_portfolio(&mut tx, owner, data.portfolio_id, &data.portfoliopassword).await?;
+    let status: String =
+        sqlx::query("SELECT status FROM orders WHERE id = $1 AND portfolio_id = $2 FOR UPDATE")
+            .bind(data.order_id)
+            .bind(portfolio.id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(TradeError::OrderNotFound)?
+            .try_get("status")?;
+
+    if status != "open" {
+        return Err(TradeError::OrderNotOpen);
+    }
+
+    sqlx::query("UPDATE orders SET status = 'cancelled', closed_at = NOW() WHERE id = $1")
+        .bind(data.order_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let order = fetch_order(&mut tx, data.order_id).await?;
+    tx.commit().await?;
+    Ok(order)
+}
+
+pub async fn cancelorder(
+    data: Valid<CancelOrderData>,
+    db_pool: web::Data<PgPool>,
+    bus: web::Data<EventBus>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let order = cancel_order(db_pool.get_ref(), &user.email, &data).await?;
+    bus.publish(Event::Order {
+        owner: user.email.clone(),
+        portfolio_id: data.portfolio_id,
+        order: order.clone(),
+    })
+    .await;
+    Ok(HttpResponse::Ok().json(order))
+}
+
+/// What filling an order changed.
+struct Filled {
+    order: Order,
+    /// Triggers on the same coin that had nothing left to sell once it went through.
+    cancelled: Vec<Order>,
+}
+
+/// Fills one order at the current price if that price still crosses its limit.
+/// Locks portfolio, then order, then crypto, the same order the handlers use.
+async fn fill_order(
+    pool: &PgPool,
+    order_id: i32,
+    portfolio_id: i32,
+) -> Result<Option<Filled>, TradeError> {
+    let mut tx = pool.begin().await?;
+
+    let portfolio = lock_portfolio_by_id(&mut tx, portfolio_id).await?;
+    let order = sqlx::query(
+        "SELECT crypto_id, side, kind, amount, limit_price, status FROM orders WHERE id = $1 FOR UPDATE",
+    )
+    .bind(order_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(TradeError::OrderNotFound)?;
+
+    let status: String = order.try_get("status")?;
+    if status != "open" {
+        return Ok(None);
+    }
+    let crypto_id: i32 = order.try_get("crypto_id")?;
+    let side: String = order.try_get("side")?;
+    let kind: String = order.try_get("kind")?;
+    let amount: Decimal = order.try_get("amount")?;
+    let limit_price: Decimal = order.try_get("limit_price")?;
+
+    let crypto = lock_crypto_by_id(&mut tx, crypto_id).await?;
+    let fees = fee_schedule(&mut tx, crypto.id).await?;
+    let fill_price = if side == "buy" {
+        fees.ask(crypto.price)?
+    } else {
+        fees.bid(crypto.price)
+    };
+    // Limit orders wait for the price they would actually trade at, triggers fire on the price itself
+    let quote = if kind == "limit" {
+        fill_price
+    } else {
+        crypto.price
+    };
+    if !crosses(&kind, &side, quote, limit_price) {
+        return Ok(None);
+    }
+
+    // A trigger sells whatever is left of the holding if it shrank since it was placed
+    let filled_amount = if kind == "limit" {
+        amount
+    } else {
+        let held = held_crypto(&mut tx, portfolio.id, crypto.id).await?;
+        let reserved = reserved_crypto(&mut tx, portfolio.id, crypto.id).await?;
+        let available = held - reserved;
+        if available <= Decimal::ZERO {
+            return Err(TradeError::NotEnoughCrypto);
+        }
+        amount.min(available)
+    };
+
+    // Close the order first so its own reservation doesn't block the trade
+    sqlx::query(
+        "UPDATE orders SET status = 'filled', fill_price = $1, filled_amount = $2, closed_at = NOW() WHERE id = $3",
+    )
+    .bind(fill_price)
+    .bind(filled_amount)
+    .bind(order_id)
+    .execute(&mut *tx)
+    .await?;
+
+    if side == "buy" {
+        buy_in_tx(&mut tx, &portfolio, &crypto, filled_amount).await?;
+    } else {
+        sell_in_tx(&mut tx, &portfolio, &crypto, filled_amount).await?;
+    }
+
+    // Once a trigger has sold the whole holding its siblings have nothing left to protect
+    let mut cancelled = Vec::new();
+    if kind != "limit"
+        && held_crypto(&mut tx, portfolio.id, crypto.id)
+            .await?
+            .is_zero()
+    {
+        let rows = sqlx::query(
+            "UPDATE orders SET status = 'cancelled', closed_at = NOW() WHERE portfolio_id = $1 AND crypto_id = $2 AND kind <> 'limit' AND status = 'open' RETURNING id",
+        )
+        .bind(portfolio.id)
+        .bind(crypto.id)
+        .fetch_all(&mut *tx)
+        .await?;
+        for row in rows {
+            cancelled.push(fetch_order(&mut tx, row.try_get("id")?).await?);
+        }
+    }
+
+    let order = fetch_order(&mut tx, order_id).await?;
+    tx.commit().await?;
+    println!(
+        "Filled {} {} order {} for {} of {} at {}",
+        kind,
+        side,
+        order_id,
+        filled_amount.normalize(),
+        crypto.name,
+        fill_price
+    );
+    Ok(Some(Filled { order, cancelled }))
+}
+
+/// Gives up on an order the portfolio can no longer cover.
+async fn reject_order(pool: &PgPool, order_id: i32) -> Result<Option<Order>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let rejected = sqlx::query(
+        "UPDATE orders SET status = 'rejected', closed_at = NOW() WHERE id = $1 AND status = 'open'",
+    )
+    .bind(order_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    let order = if rejected == 0 {
+        None
+    } else {
+        Some(fetch_order(&mut tx, order_id).await?)
+    };
+    tx.commit().await?;
+    Ok(order)
+}
+
+pub async fn match_open_orders(pool: &PgPool, bus: &EventBus) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT o.id, o.portfolio_id, p.owner FROM orders o
+        JOIN crypto c ON c.id = o.crypto_id
+        JOIN portfolios p ON p.id = o.portfolio_id
+        WHERE o.status = 'open'
+          AND (((o.kind = 'limit' AND o.side = 'buy') OR o.kind = 'stop_loss')
+                AND c.price <= o.limit_price
+            OR ((o.kind = 'limit' AND o.side = 'sell') OR o.kind = 'take_profit')
+                AND c.price >= o.limit_price)
+        ORDER BY o.created_at, o.id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for row in rows {
+        let order_id: i32 = row.try_get("id")?;
+        let portfolio_id: i32 = row.try_get("portfolio_id")?;
+        let owner: String = row.try_get("owner")?;
+        match fill_order(pool, order_id, portfolio_id).await {
+            Ok(Some(Filled { order, cancelled })) => {
+                bus.publish(Event::Fill {
+                    owner: owner.clone(),
+                    portfolio_id,
+                    order,
+                })
+                .await;
+                for order in cancelled {
+                    bus.publish(Event::Order {
+                        owner: owner.clone(),
+                        portfolio_id,
+                        order,
+                    })
+                    .await;
+                }
+            }
+            Ok(None) => {}
+            Err(TradeError::Database(e)) => return Err(e),
+            Err(e) => {
+                // The portfolio can no longer cover the order, so stop retrying it
+                eprintln!("Rejecting order {}: {:?}", order_id, e);
+                if let Some(order) = reject_order(pool, order_id).await? {
+                    bus.publish(Event::Order {
+                        owner,
+                        portfolio_id,
+                        order,
+                    })
+                    .await;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Background task that fills resting orders and fires stop-loss and take-profit
+/// triggers once `change_price_handler` moves the price across them.
+pub async fn run_matching_loop(pool: PgPool, bus: web::Data<EventBus>) {
+    let mut interval = tokio::time::interval(MATCHING_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = match_open_orders(&pool, &bus).await {
+            eprintln!("Error matching orders: {}", e);
+        }
+    }
+}
@@ -0,0 +1,579 @@
+use crate::apierror::ApiError;
+use crate::auth::{AdminUser, AuthenticatedUser, ModeratorUser};
+use crate::handlerscryptoapi::{
+    LockedPortfolio, TradeError, lock_owned_portfolio, lock_portfolio, lock_portfolio_by_id,
+    reserved_money,
+};
+use crate::handlersorders::{Order, open_orders};
+use crate::money;
+use crate::validation::{self, Valid};
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Row, Transaction};
+use std::collections::HashMap;
+use validator::Validate;
+
+const DEFAULT_PER_PAGE: i64 = 50;
+const MAX_PER_PAGE: i64 = 200;
+/// Far past any real history, low enough that the offset can't overflow.
+const MAX_PAGE: i64 = 1_000_000;
+
+/// What moved a portfolio's cash or coins. Deposits of starting money and imported
+/// legacy lots are written by the SQL that creates them.
+#[derive(Debug, Clone, Copy)]
+pub enum TransactionKind {
+    Buy,
+    Sell,
+    /// A correction of the cash balance by an admin.
+    Adjustment,
+}
+
+impl TransactionKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TransactionKind::Buy => "buy",
+            TransactionKind::Sell => "sell",
+            TransactionKind::Adjustment => "adjustment",
+        }
+    }
+}
+
+/// One row of the ledger. `cash_change` is signed and `balance` is the cash
+/// left after it.
+pub struct LedgerEntry<'a> {
+    pub portfolio_id: i32,
+    pub kind: TransactionKind,
+    pub crypto: Option<(i32, &'a str)>,
+    pub quantity: Decimal,
+    pub price: Decimal,
+    pub fee: Decimal,
+    pub cash_change: Decimal,
+    pub balance: Decimal,
+    pub note: Option<&'a str>,
+}
+
+/// Appends to the ledger inside the transaction that made the change.
+pub async fn record_transaction(
+    tx: &mut Transaction<'_, Postgres>,
+    entry: &LedgerEntry<'_>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO transactions (portfolio_id, kind, crypto_id, crypto, quantity, price, fee, cash_change, balance, note) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+    )
+    .bind(entry.portfolio_id)
+    .bind(entry.kind.as_str())
+    .bind(entry.crypto.map(|(id, _)| id))
+    .bind(entry.crypto.map(|(_, name)| name))
+    .bind(entry.quantity)
+    .bind(entry.price)
+    .bind(entry.fee)
+    .bind(entry.cash_change)
+    .bind(entry.balance)
+    .bind(entry.note)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct PortfolioSummaryData {
+    #[validate(range(min = 1))]
+    portfolio_id: i32,
+    #[validate(length(min = 1, max = 128))]
+    portfoliopassword: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HoldingSummary {
+    crypto: String,
+    amount: Decimal,
+    average_cost: Decimal,
+    price: Decimal,
+    cost_basis: Decimal,
+    market_value: Decimal,
+    unrealized_pnl: Decimal,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PortfolioSummary {
+    id: i32,
+    name: String,
+    created_at: Option<DateTime<Utc>>,
+    cash: Decimal,
+    /// Part of `cash` set aside for open limit buys.
+    reserved_cash: Decimal,
+    holdings: Vec<HoldingSummary>,
+    market_value: Decimal,
+    total_value: Decimal,
+    unrealized_pnl: Decimal,
+    realized_pnl: Decimal,
+    starting_money: Decimal,
+    total_return: Decimal,
+    total_return_percent: f64,
+}
+
+async fn portfolio_summary(
+    pool: &PgPool,
+    owner: &str,
+    data: &PortfolioSummaryData,
+) -> Result<PortfolioSummary, TradeError> {
+    // Locking keeps cash and holdings from a single moment
+    let mut tx = pool.begin().await?;
+    let portfolio =
+        lock_portfolio(&mut tx, owner, data.portfolio_id, &data.portfoliopassword).await?;
+    let summary = summarize(&mut tx, &portfolio).await?;
+    tx.commit().await?;
+    Ok(summary)
+}
+
+/// Values a locked portfolio's holdings at the current prices.
+async fn summarize(
+    tx: &mut Transaction<'_, Postgres>,
+    portfolio: &LockedPortfolio,
+) -> Result<PortfolioSummary, TradeError> {
+    let totals = sqlx::query(
+        "SELECT name, created_at, starting_money, realized_pnl FROM portfolios WHERE id = $1",
+    )
+    .bind(portfolio.id)
+    .fetch_one(&mut **tx)
+    .await?;
+    let name: String = totals.try_get("name")?;
+    let starting_money: Decimal = totals.try_get("starting_money")?;
+    let realized_pnl: Decimal = totals.try_get("realized_pnl")?;
+    let reserved_cash = reserved_money(tx, portfolio.id).await?;
+
+    let rows = sqlx::query(
+        r#"
+        SELECT c.name, c.price,
+            SUM(h.amount) AS amount,
+            SUM(h.amount * h.price_bought) AS cost_basis
+        FROM holdings h
+        JOIN crypto c ON c.id = h.crypto_id
+        WHERE h.portfolio_id = $1
+        GROUP BY c.id
+        ORDER BY c.name
+        "#,
+    )
+    .bind(portfolio.id)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let mut holdings = Vec::with_capacity(rows.len());
+    let mut market_value = Decimal::ZERO;
+    let mut unrealized_pnl = Decimal::ZERO;
+    for row in rows {
+        let amount: Decimal = row.try_get("amount")?;
+        let price: Decimal = row.try_get("price")?;
+        let exact_cost: Decimal = row.try_get("cost_basis")?;
+        let cost_basis = money::cents_up(exact_cost);
+        let value = money::cents_down(money::checked(amount.checked_mul(price))?);
+        let pnl = money::checked(value.checked_sub(cost_basis))?;
+        market_value = money::checked(market_value.checked_add(value))?;
+        unrealized_pnl = money::checked(unrealized_pnl.checked_add(pnl))?;
+        holdings.push(HoldingSummary {
+            crypto: row.try_get("name")?,
+            amount: amount.normalize(),
+            average_cost: money::cents_up(money::checked(exact_cost.checked_div(amount))?),
+            price,
+            cost_basis,
+            market_value: value,
+            unrealized_pnl: pnl,
+        });
+    }
+
+    let total_value = money::checked(portfolio.money.checked_add(market_value))?;
+    let total_return = money::checked(total_value.checked_sub(starting_money))?;
+
+    Ok(PortfolioSummary {
+        id: portfolio.id,
+        name,
+        created_at: totals.try_get("created_at")?,
+        cash: portfolio.money,
+        reserved_cash,
+        holdings,
+        market_value,
+        total_value,
+        unrealized_pnl,
+        realized_pnl,
+        starting_money,
+        total_return,
+        total_return_percent: percent_of(total_return, starting_money),
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct PortfolioListing {
+    id: i32,
+    name: String,
+    cash: Decimal,
+    created_at: Option<DateTime<Utc>>,
+}
+
+/// The caller's portfolios, oldest first.
+pub async fn listportfolios(
+    user: AuthenticatedUser,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ApiError> {
+    let rows = sqlx::query(
+        "SELECT id, name, money, created_at FROM portfolios WHERE owner = $1 ORDER BY id",
+    )
+    .bind(&user.email)
+    .fetch_all(db_pool.get_ref())
+    .await?;
+
+    let portfolios = rows
+        .iter()
+        .map(|row| {
+            Ok(PortfolioListing {
+                id: row.try_get("id")?,
+                name: row.try_get("name")?,
+                cash: row.try_get("money")?,
+                created_at: row.try_get("created_at")?,
+            })
+        })
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    Ok(HttpResponse::Ok().json(portfolios))
+}
+
+#[derive(Serialize, Debug)]
+pub struct PortfolioDetail {
+    #[serde(flatten)]
+    summary: PortfolioSummary,
+    open_orders: Vec<Order>,
+}
+
+async fn portfolio_detail(
+    pool: &PgPool,
+    owner: &str,
+    portfolio_id: i32,
+) -> Result<PortfolioDetail, TradeError> {
+    let mut tx = pool.begin().await?;
+    let portfolio = lock_owned_portfolio(&mut tx, owner, portfolio_id).await?;
+    let summary = summarize(&mut tx, &portfolio).await?;
+    let open_orders = open_orders(&mut tx, portfolio.id).await?;
+    tx.commit().await?;
+    Ok(PortfolioDetail {
+        summary,
+        open_orders,
+    })
+}
+
+/// One of the caller's portfolios with its holdings and open orders. Being logged in
+/// as the owner is enough to look, trading still needs the portfolio's password.
+pub async fn portfoliodetail(
+    portfolio_id: web::Path<i32>,
+    user: AuthenticatedUser,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ApiError> {
+    let detail = portfolio_detail(db_pool.get_ref(), &user.email, *portfolio_id).await?;
+    Ok(HttpResponse::Ok().json(detail))
+}
+
+/// `part` as a percentage of `whole`, for display only.
+pub fn percent_of(part: Decimal, whole: Decimal) -> f64 {
+    let part = part.to_f64().unwrap_or_default();
+    let whole = whole.to_f64().unwrap_or_default();
+    part * 100.0 / whole
+}
+
+/// What a portfolio is worth right now and how it got there.
+pub async fn portfoliosummary(
+    data: Valid<PortfolioSummaryData>,
+    db_pool: web::Data<PgPool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let summary = portfolio_summary(db_pool.get_ref(), &user.email, &data).await?;
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct PortfolioHistoryData {
+    #[validate(range(min = 1))]
+    portfolio_id: i32,
+    #[validate(length(min = 1, max = 128))]
+    portfoliopassword: String,
+    /// Starts at 1, newest entries first.
+    #[validate(range(min = 1, max = MAX_PAGE))]
+    page: Option<i64>,
+    #[validate(range(min = 1))]
+    per_page: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LedgerTransaction {
+    id: i64,
+    kind: String,
+    crypto: Option<String>,
+    quantity: Decimal,
+    price: Decimal,
+    fee: Decimal,
+    cash_change: Decimal,
+    balance: Decimal,
+    note: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PortfolioHistory {
+    page: i64,
+    per_page: i64,
+    total: i64,
+    transactions: Vec<LedgerTransaction>,
+}
+
+async fn portfolio_history(
+    pool: &PgPool,
+    owner: &str,
+    data: &PortfolioHistoryData,
+) -> Result<PortfolioHistory, TradeError> {
+    let page = data.page.unwrap_or(1).max(1);
+    let per_page = data
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+
+    let mut tx = pool.begin().await?;
+    let portfolio =
+        lock_portfolio(&mut tx, owner, data.portfolio_id, &data.portfoliopassword).await?;
+
+    let total: i64 =
+        sqlx::query("SELECT COUNT(*) AS total FROM transactions WHERE portfolio_id = $1")
+            .bind(portfolio.id)
+            .fetch_one(&mut *tx)
+            .await?
+            .try_get("total")?;
+    let rows = sqlx::query(
+        "SELECT id, kind, crypto, quantity, price, fee, cash_change, balance, note, created_at FROM transactions WHERE portfolio_id = $1 ORDER BY id DESC LIMIT $2 OFFSET $3",
+    )
+    .bind(portfolio.id)
+    .bind(per_page)
+    .bind((page - 1) * per_page)
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let mut transactions = Vec::with_capacity(rows.len());
+    for row in rows {
+        transactions.push(LedgerTransaction {
+            id: row.try_get("id")?,
+            kind: row.try_get("kind")?,
+            crypto: row.try_get("crypto")?,
+            quantity: row.try_get::<Decimal, _>("quantity")?.normalize(),
+            price: row.try_get("price")?,
+            fee: row.try_get("fee")?,
+            cash_change: row.try_get("cash_change")?,
+            balance: row.try_get("balance")?,
+            note: row.try_get("note")?,
+            created_at: row.try_get("created_at")?,
+        });
+    }
+
+    Ok(PortfolioHistory {
+        page,
+        per_page,
+        total,
+        transactions,
+    })
+}
+
+/// Every buy, sell, deposit and adjustment of a portfolio, newest first.
+pub async fn portfoliohistory(
+    data: Valid<PortfolioHistoryData>,
+    db_pool: web::Data<PgPool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let history = portfolio_history(db_pool.get_ref(), &user.email, &data).await?;
+    Ok(HttpResponse::Ok().json(history))
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct AdjustBalanceData {
+    #[validate(range(min = 1))]
+    portfolio_id: i32,
+    /// Added to the cash balance, negative to take money away.
+    #[validate(custom(function = "validation::adjustment"))]
+    amount: Decimal,
+    #[validate(length(min = 1, max = 200))]
+    note: String,
+}
+
+async fn adjust_balance(
+    pool: &PgPool,
+    data: &AdjustBalanceData,
+    admin: &str,
+) -> Result<Decimal, TradeError> {
+    let mut tx = pool.begin().await?;
+    let portfolio = lock_portfolio_by_id(&mut tx, data.portfolio_id).await?;
+
+    // Money reserved for open limit buys can't be taken away
+    let balance = money::checked(portfolio.money.checked_add(data.amount))?;
+    if balance < reserved_money(&mut tx, portfolio.id).await? {
+        return Err(TradeError::NotEnoughMoney);
+    }
+
+    sqlx::query("UPDATE portfolios SET money = $1 WHERE id = $2")
+        .bind(balance)
+        .bind(portfolio.id)
+        .execute(&mut *tx)
+        .await?;
+    let note = format!("{} (by {})", data.note, admin);
+    record_transaction(
+        &mut tx,
+        &LedgerEntry {
+            portfolio_id: portfolio.id,
+            kind: TransactionKind::Adjustment,
+            crypto: None,
+            quantity: Decimal::ZERO,
+            price: Decimal::ZERO,
+            fee: Decimal::ZERO,
+            cash_change: data.amount,
+            balance,
+            note: Some(&note),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(balance)
+}
+
+/// Lets an admin correct a portfolio's cash. The change goes into the ledger like any other.
+pub async fn adjustbalance(
+    admin: AdminUser,
+    data: Valid<AdjustBalanceData>,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ApiError> {
+    let balance = adjust_balance(db_pool.get_ref(), &data, &admin.email).await?;
+    Ok(HttpResponse::Ok().body(format!(
+        "Adjusted portfolio {} by {}, balance is now {}",
+        data.portfolio_id, data.amount, balance
+    )))
+}
+
+/// A portfolio whose ledger doesn't replay to its current state.
+#[derive(Serialize, Debug)]
+pub struct ReconciliationIssue {
+    portfolio_id: i32,
+    portfolio: String,
+    problem: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Reconciliation {
+    checked: usize,
+    issues: Vec<ReconciliationIssue>,
+}
+
+/// Replays every portfolio's ledger from the first entry and compares the result
+/// with its cash and holdings.
+pub async fn reconcile(pool: &PgPool) -> Result<Reconciliation, sqlx::Error> {
+    // One snapshot for everything, so trades happening meanwhile don't show up as issues
+    let mut tx = pool.begin().await?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+        .execute(&mut *tx)
+        .await?;
+
+    let portfolios = sqlx::query("SELECT id, name, money FROM portfolios ORDER BY id")
+        .fetch_all(&mut *tx)
+        .await?;
+    let mut issues = Vec::new();
+
+    for portfolio in &portfolios {
+        let portfolio_id: i32 = portfolio.try_get("id")?;
+        let name: String = portfolio
+            .try_get::<Option<String>, _>("name")?
+            .unwrap_or_default();
+        let money: Decimal = portfolio.try_get("money")?;
+        let mut problems = Vec::new();
+
+        let entries = sqlx::query(
+            "SELECT id, kind, crypto_id, quantity, cash_change, balance FROM transactions WHERE portfolio_id = $1 ORDER BY id",
+        )
+        .bind(portfolio_id)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        // Sums of stored amounts, which stay far away from what a Decimal can hold
+        let mut cash = Decimal::ZERO;
+        let mut coins: HashMap<i32, Decimal> = HashMap::new();
+        for entry in &entries {
+            let id: i64 = entry.try_get("id")?;
+            let kind: String = entry.try_get("kind")?;
+            let cash_change: Decimal = entry.try_get("cash_change")?;
+            let balance: Decimal = entry.try_get("balance")?;
+            cash += cash_change;
+            if cash != balance {
+                problems.push(format!(
+                    "entry {} says the balance is {}, replay gives {}",
+                    id, balance, cash
+                ));
+            }
+
+            if let Some(crypto_id) = entry.try_get::<Option<i32>, _>("crypto_id")? {
+                let quantity: Decimal = entry.try_get("quantity")?;
+                let held = coins.entry(crypto_id).or_default();
+                match kind.as_str() {
+                    "buy" | "import" => *held += quantity,
+                    "sell" => *held -= quantity,
+                    _ => {}
+                }
+            }
+        }
+        if cash != money {
+            problems.push(format!("cash is {}, replay gives {}", money, cash));
+        }
+
+        let holdings = sqlx::query(
+            "SELECT crypto_id, SUM(amount) AS amount FROM holdings WHERE portfolio_id = $1 GROUP BY crypto_id",
+        )
+        .bind(portfolio_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        for holding in &holdings {
+            let crypto_id: i32 = holding.try_get("crypto_id")?;
+            let amount: Decimal = holding.try_get("amount")?;
+            let replayed = coins.remove(&crypto_id).unwrap_or_default();
+            if replayed != amount {
+                problems.push(format!(
+                    "holds {} of crypto {}, replay gives {}",
+                    amount.normalize(),
+                    crypto_id,
+                    replayed.normalize()
+                ));
+            }
+        }
+        for (crypto_id, replayed) in coins {
+            if !replayed.is_zero() {
+                problems.push(format!(
+                    "holds 0 of crypto {}, replay gives {}",
+                    crypto_id,
+                    replayed.normalize()
+                ));
+            }
+        }
+
+        issues.extend(problems.into_iter().map(|problem| ReconciliationIssue {
+            portfolio_id,
+            portfolio: name.clone(),
+            problem,
+        }));
+    }
+
+    tx.commit().await?;
+    Ok(Reconciliation {
+        checked: portfolios.len(),
+        issues,
+    })
+}
+
+/// Admin check that every portfolio's ledger adds up to its current cash and holdings.
+pub async fn reconcileledger(
+    _moderator: ModeratorUser,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ApiError> {
+    let reconciliation = reconcile(db_pool.get_ref()).await?;
+    Ok(HttpResponse::Ok().json(reconciliation))
+}
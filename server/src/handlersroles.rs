@@ -0,0 +1,135 @@
+use crate::apierror::ApiError;
+use crate::auth::{AdminUser, Role};
+use crate::validation::Valid;
+use actix_web::{HttpResponse, web};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use validator::Validate;
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct GrantRoleData {
+    #[validate(length(min = 1, max = 255))]
+    email: String,
+    role: Role,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct RevokeRoleData {
+    #[validate(length(min = 1, max = 255))]
+    email: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StaffMember {
+    email: String,
+    role: Role,
+}
+
+#[derive(Debug)]
+enum RoleChangeError {
+    UnknownUser,
+    LastAdmin,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for RoleChangeError {
+    fn from(e: sqlx::Error) -> Self {
+        RoleChangeError::Database(e)
+    }
+}
+
+impl From<RoleChangeError> for ApiError {
+    fn from(e: RoleChangeError) -> Self {
+        match e {
+            RoleChangeError::UnknownUser => ApiError::UserNotFound,
+            RoleChangeError::LastAdmin => ApiError::LastAdmin,
+            RoleChangeError::Database(e) => {
+                ApiError::Internal(format!("DB error (role change): {}", e))
+            }
+        }
+    }
+}
+
+/// Sets a user's role. The admin rows are locked first, so two admins demoting
+/// each other at the same time can't leave nobody in charge.
+async fn set_role(pool: &PgPool, email: &str, role: Role) -> Result<Role, RoleChangeError> {
+    let mut tx = pool.begin().await?;
+
+    let admins: Vec<String> =
+        sqlx::query("SELECT email FROM users WHERE role = 'admin' ORDER BY id FOR UPDATE")
+            .fetch_all(&mut *tx)
+            .await?
+            .iter()
+            .map(|row| row.get("email"))
+            .collect();
+
+    let previous: String = sqlx::query("SELECT role FROM users WHERE email = $1 FOR UPDATE")
+        .bind(email)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(RoleChangeError::UnknownUser)?
+        .try_get("role")?;
+    let previous = Role::parse(&previous).unwrap_or(Role::Player);
+
+    if previous == Role::Admin && role != Role::Admin && admins.len() <= 1 {
+        return Err(RoleChangeError::LastAdmin);
+    }
+
+    sqlx::query("UPDATE users SET role = $1 WHERE email = $2")
+        .bind(role.as_str())
+        .bind(email)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(previous)
+}
+
+pub async fn grantrole(
+    data: Valid<GrantRoleData>,
+    db_pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    let previous = set_role(db_pool.get_ref(), &data.email, data.role).await?;
+    println!(
+        "{} changed the role of {} from {} to {}",
+        admin.email, data.email, previous, data.role
+    );
+    Ok(HttpResponse::Ok().body(format!("{} is now {}", data.email, data.role)))
+}
+
+/// Takes a user back to a plain player.
+pub async fn revokerole(
+    data: Valid<RevokeRoleData>,
+    db_pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    let previous = set_role(db_pool.get_ref(), &data.email, Role::Player).await?;
+    println!(
+        "{} revoked the {} role of {}",
+        admin.email, previous, data.email
+    );
+    Ok(HttpResponse::Ok().body(format!("{} is now {}", data.email, Role::Player)))
+}
+
+/// Everyone above a player.
+pub async fn listroles(
+    _admin: AdminUser,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ApiError> {
+    let rows =
+        sqlx::query("SELECT email, role FROM users WHERE role <> 'player' ORDER BY role, email")
+            .fetch_all(db_pool.get_ref())
+            .await?;
+
+    let staff: Vec<StaffMember> = rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(StaffMember {
+                email: row.try_get("email").ok()?,
+                role: Role::parse(row.try_get("role").ok()?)?,
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(staff))
+}
@@ -0,0 +1,143 @@
+use crate::apierror::ApiError;
+use crate::events::{EventBus, Published};
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse, web};
+use futures_util::stream;
+use sqlx::{PgPool, Row};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// How often a comment is sent when nothing else is, so proxies keep the
+/// connection open and clients that went away are noticed.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// How many recorded events are read at a time while a client catches up.
+const REPLAY_BATCH: i64 = 500;
+
+/// One client's place in the stream of market events.
+struct Feed {
+    pool: PgPool,
+    events: broadcast::Receiver<Published>,
+    /// The last event sent, or where the client asked to resume from.
+    last_id: i64,
+    /// Live events up to here were already sent from the database.
+    replayed_to: i64,
+    /// Recorded events newer than `last_id` may be missing from `backlog`.
+    behind: bool,
+    backlog: VecDeque<(i64, String, String)>,
+    keepalive: tokio::time::Interval,
+}
+
+impl Feed {
+    /// The next piece of the response, or `None` to end it.
+    async fn next(mut self) -> Option<(Result<Bytes, actix_web::Error>, Feed)> {
+        loop {
+            if let Some((id, kind, payload)) = self.backlog.pop_front() {
+                self.last_id = id;
+                return Some((Ok(frame(id, &kind, &payload)), self));
+            }
+            if self.behind {
+                if let Err(e) = self.catch_up().await {
+                    eprintln!("Error replaying market events: {}", e);
+                    return None;
+                }
+                continue;
+            }
+            tokio::select! {
+                published = self.events.recv() => match published {
+                    Ok(Published { id: Some(id), event }) if id > self.replayed_to => {
+                        let Ok(payload) = serde_json::to_string(&event) else {
+                            continue;
+                        };
+                        self.last_id = self.last_id.max(id);
+                        return Some((Ok(frame(id, event.kind(), &payload)), self));
+                    }
+                    // Private events and ones already sent
+                    Ok(_) => {}
+                    // What was missed is still in the database
+                    Err(RecvError::Lagged(_)) => self.behind = true,
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = self.keepalive.tick() => {
+                    return Some((Ok(Bytes::from_static(b": keepalive\n\n")), self));
+                }
+            }
+        }
+    }
+
+    /// Loads the next batch of recorded events after `last_id`.
+    async fn catch_up(&mut self) -> Result<(), sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT id, kind, payload::text AS payload FROM market_events WHERE id > $1 ORDER BY id LIMIT $2",
+        )
+        .bind(self.last_id)
+        .bind(REPLAY_BATCH)
+        .fetch_all(&self.pool)
+        .await?;
+
+        self.behind = rows.len() as i64 == REPLAY_BATCH;
+        for row in rows {
+            let id: i64 = row.try_get("id")?;
+            self.replayed_to = self.replayed_to.max(id);
+            self.backlog
+                .push_back((id, row.try_get("kind")?, row.try_get("payload")?));
+        }
+        Ok(())
+    }
+}
+
+fn frame(id: i64, kind: &str, payload: &str) -> Bytes {
+    Bytes::from(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        id, kind, payload
+    ))
+}
+
+/// Streams price changes and coins being listed and delisted as server-sent
+/// events. Clients that send `Last-Event-ID` get what they missed first.
+pub async fn eventstream(
+    req: HttpRequest,
+    db_pool: web::Data<PgPool>,
+    bus: web::Data<EventBus>,
+) -> Result<HttpResponse, ApiError> {
+    let resume_from = match req.headers().get("Last-Event-ID") {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|id| id.trim().parse::<i64>().ok())
+                .ok_or(ApiError::BadRequest("Last-Event-ID must be an event id"))?,
+        ),
+        None => None,
+    };
+
+    // Subscribe first, anything recorded from here on arrives live
+    let events = bus.subscribe();
+    // Without an id the client only gets what happens from now on
+    let (last_id, replayed_to) = match resume_from {
+        Some(id) => (id, id),
+        None => {
+            let latest: i64 = sqlx::query("SELECT COALESCE(MAX(id), 0) AS id FROM market_events")
+                .fetch_one(db_pool.get_ref())
+                .await?
+                .try_get("id")?;
+            (latest, 0)
+        }
+    };
+    let feed = Feed {
+        pool: db_pool.get_ref().clone(),
+        events,
+        last_id,
+        replayed_to,
+        behind: resume_from.is_some(),
+        backlog: VecDeque::new(),
+        keepalive: tokio::time::interval(KEEPALIVE_INTERVAL),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(stream::unfold(feed, Feed::next)))
+}
@@ -0,0 +1,258 @@
+use crate::apierror::ApiError;
+use crate::auth::AuthenticatedUser;
+use crate::events::{Event, EventBus, Published, Subscription};
+use crate::validation;
+use actix_web::{HttpRequest, HttpResponse, web};
+use actix_ws::{CloseCode, CloseReason, Closed, Message, MessageStream, Session};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use validator::Validate;
+
+/// How often the server pings the client.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Clients that send nothing, not even a pong, for this long are disconnected.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+/// A client that doesn't read what it's sent for this long is disconnected, so
+/// it can't keep messages piling up on the server.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many coins one connection can watch at once.
+const MAX_SUBSCRIPTIONS: usize = 64;
+
+#[derive(Deserialize, Debug, Validate)]
+struct CryptoList {
+    #[validate(length(min = 1, max = 64, message = "must list 1 to 64 coins"))]
+    cryptos: Vec<String>,
+}
+
+/// What clients send over the socket.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe(CryptoList),
+    Unsubscribe(CryptoList),
+}
+
+/// What the server sends besides events.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    /// Every coin the connection is now watching.
+    Subscribed { cryptos: Vec<&'a str> },
+    /// The client fell behind and this many events were dropped, so anything it
+    /// shows may be stale until the next ones.
+    Lagged { missed: u64 },
+    /// A message from the client was refused, with the same codes as the HTTP API.
+    Error {
+        code: &'static str,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fields: Option<&'a BTreeMap<String, Vec<String>>>,
+    },
+}
+
+/// The connection is gone, or should be closed with the given reason.
+struct Disconnect(Option<CloseReason>);
+
+/// Pushes price changes of the coins a client subscribes to, and fills and order
+/// updates for the logged in player's portfolios.
+pub async fn websocket(
+    req: HttpRequest,
+    body: web::Payload,
+    user: AuthenticatedUser,
+    db_pool: web::Data<PgPool>,
+    bus: web::Data<EventBus>,
+) -> Result<HttpResponse, ApiError> {
+    let (response, session, messages) = actix_ws::handle(&req, body)
+        .map_err(|_| ApiError::BadRequest("Expected a WebSocket upgrade"))?;
+
+    // Subscribe before answering so nothing published after the upgrade is missed
+    let events = bus.subscribe();
+    actix_web::rt::spawn(async move {
+        let subscription = Subscription::new(user.email);
+        let reason = run_socket(&db_pool, session.clone(), messages, events, subscription).await;
+        // A client that stopped reading would block this forever
+        let _ = tokio::time::timeout(SEND_TIMEOUT, session.close(reason.0)).await;
+    });
+    Ok(response)
+}
+
+async fn run_socket(
+    pool: &PgPool,
+    mut session: Session,
+    mut messages: MessageStream,
+    mut events: tokio::sync::broadcast::Receiver<Published>,
+    mut subscription: Subscription,
+) -> Disconnect {
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut last_heard = Instant::now();
+
+    loop {
+        let step = tokio::select! {
+            message = messages.recv() => {
+                last_heard = Instant::now();
+                match message {
+                    Some(Ok(Message::Text(text))) => {
+                        handle_message(pool, &mut session, &mut subscription, &text).await
+                    }
+                    Some(Ok(Message::Ping(bytes))) => deliver(session.pong(&bytes)).await,
+                    Some(Ok(Message::Close(reason))) => Err(Disconnect(reason)),
+                    Some(Ok(_)) => Ok(()),
+                    Some(Err(e)) => Err(Disconnect(Some(CloseReason {
+                        code: CloseCode::Protocol,
+                        description: Some(e.to_string()),
+                    }))),
+                    None => Err(Disconnect(None)),
+                }
+            }
+            event = events.recv() => match event {
+                Ok(published) if subscription.wants(&published.event) => {
+                    send_json(&mut session, &published.event).await
+                }
+                Ok(_) => Ok(()),
+                Err(RecvError::Lagged(missed)) => {
+                    send_json(&mut session, &ServerMessage::Lagged { missed }).await
+                }
+                Err(RecvError::Closed) => Err(Disconnect(Some(CloseCode::Away.into()))),
+            },
+            _ = heartbeat.tick() => {
+                if last_heard.elapsed() > CLIENT_TIMEOUT {
+                    Err(Disconnect(Some(CloseReason {
+                        code: CloseCode::Policy,
+                        description: Some("Heartbeat timed out".to_string()),
+                    })))
+                } else {
+                    deliver(session.ping(b"")).await
+                }
+            }
+        };
+        if let Err(disconnect) = step {
+            return disconnect;
+        }
+    }
+}
+
+async fn handle_message(
+    pool: &PgPool,
+    session: &mut Session,
+    subscription: &mut Subscription,
+    text: &str,
+) -> Result<(), Disconnect> {
+    match apply_message(pool, subscription, text).await {
+        Ok(snapshot) => {
+            let cryptos = subscription.cryptos.iter().map(String::as_str).collect();
+            send_json(session, &ServerMessage::Subscribed { cryptos }).await?;
+            for event in &snapshot {
+                send_json(session, event).await?;
+            }
+            Ok(())
+        }
+        Err(e) => {
+            if let ApiError::Internal(details) = &e {
+                eprintln!("Internal error: {}", details);
+            }
+            let fields = match &e {
+                ApiError::Invalid(invalid) => Some(&invalid.fields),
+                _ => None,
+            };
+            let message = ServerMessage::Error {
+                code: e.code(),
+                message: e.to_string(),
+                fields,
+            };
+            send_json(session, &message).await
+        }
+    }
+}
+
+/// Changes what the connection is subscribed to. Returns the current prices of
+/// newly subscribed coins, so clients don't have to wait for the next change.
+async fn apply_message(
+    pool: &PgPool,
+    subscription: &mut Subscription,
+    text: &str,
+) -> Result<Vec<Event>, ApiError> {
+    let message: ClientMessage =
+        serde_json::from_str(text).map_err(|e| ApiError::MalformedBody(e.to_string()))?;
+    match message {
+        ClientMessage::Subscribe(list) => {
+            validation::validate(&list)?;
+            let requested: BTreeSet<String> = list.cryptos.into_iter().collect();
+            let snapshot = current_prices(pool, &requested).await?;
+            if snapshot.len() < requested.len() {
+                return Err(ApiError::CryptoNotFound);
+            }
+            let mut cryptos = subscription.cryptos.clone();
+            cryptos.extend(requested);
+            if cryptos.len() > MAX_SUBSCRIPTIONS {
+                return Err(ApiError::BadRequest("Too many subscriptions"));
+            }
+            let snapshot = snapshot
+                .into_iter()
+                .filter(|event| !subscription.wants(event))
+                .collect();
+            subscription.cryptos = cryptos;
+            Ok(snapshot)
+        }
+        ClientMessage::Unsubscribe(list) => {
+            validation::validate(&list)?;
+            for crypto in &list.cryptos {
+                subscription.cryptos.remove(crypto);
+            }
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// The price of each named coin that exists, and when it last changed.
+async fn current_prices(
+    pool: &PgPool,
+    names: &BTreeSet<String>,
+) -> Result<Vec<Event>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT c.name, c.price,
+            COALESCE((SELECT MAX(t.created_at) FROM price_ticks t WHERE t.crypto_id = c.id), NOW()) AS at
+        FROM crypto c WHERE c.name = ANY($1)
+        "#,
+    )
+    .bind(names.iter().cloned().collect::<Vec<_>>())
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(Event::Price {
+                crypto: row.try_get("name")?,
+                price: row.try_get::<Decimal, _>("price")?,
+                at: row.try_get::<DateTime<Utc>, _>("at")?,
+            })
+        })
+        .collect()
+}
+
+async fn send_json(session: &mut Session, message: &impl Serialize) -> Result<(), Disconnect> {
+    match serde_json::to_string(message) {
+        Ok(text) => deliver(session.text(text)).await,
+        Err(e) => {
+            eprintln!("Error serializing a WebSocket message: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Waits for room in the outgoing buffer, but not for long.
+async fn deliver(sent: impl Future<Output = Result<(), Closed>>) -> Result<(), Disconnect> {
+    match tokio::time::timeout(SEND_TIMEOUT, sent).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(Closed)) => Err(Disconnect(None)),
+        Err(_) => Err(Disconnect(Some(CloseReason {
+            code: CloseCode::Policy,
+            description: Some("Client is not reading".to_string()),
+        }))),
+    }
+}
@@ -1,38 +1,91 @@
+mod apierror;
+mod auth;
+mod config;
 mod database;
+mod events;
 mod handlers;
 mod handlerscrypto;
 mod handlerscryptoapi;
+mod handlersfees;
+mod handlersleaderboard;
+mod handlersorders;
+mod handlersportfolio;
+mod handlersroles;
+mod handlerssse;
+mod handlersws;
+mod migrate;
+mod money;
+mod passwords;
+mod serviceauth;
+#[cfg(test)]
+mod testdb;
+mod validation;
 use actix_web::{App, HttpServer, web};
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let pool = database::singleton_database_instance_launcher().await;
-    database::database_table_creation_function_users(&pool)
-        .await
-        .expect("Error in database_table_creation_function_users");
+    let (config, command) = match config::Config::load() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let pool = database::singleton_database_instance_launcher(&config).await;
 
-    database::database_table_creation_function_token(&pool)
-        .await
-        .expect("Error in database_table_creation_function_token");
-    database::database_check_for_outtime_tokens(&pool)
-        .await
-        .expect("Error in database_check_for_outtime_tokens");
-    database::database_table_creation_function_crypto(&pool)
-        .await
-        .expect("Error in database_check_for_outtime_crypto");
+    if let Some(config::Command::Migrate { action }) = command {
+        let result = match action.unwrap_or(config::MigrateAction::Up) {
+            config::MigrateAction::Up => migrate::migrate_up(&pool).await,
+            config::MigrateAction::Down => migrate::migrate_down(&pool).await,
+            config::MigrateAction::Status => migrate::migrate_status(&pool).await,
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    database::database_table_creation_function_whitelist(&pool)
-        .await
-        .expect("Error in database_check_for_outtime_whitelist");
-    database::database_table_creation_function_portfolios(&pool)
+    if let Err(e) = migrate::check_schema(&pool).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    database::database_import_legacy_portfolio_assets(&pool)
         .await
-        .expect("Error in database_check_for_outtime_portfolio");
+        .expect("Error in database_import_legacy_portfolio_assets");
 
-    let addr = "localhost:8080";
+    let service_keys = web::Data::new(serviceauth::ServiceKeys::new(
+        config.service_keys_file.clone(),
+    ));
+    let bus = web::Data::new(events::EventBus::new(pool.clone(), events::EVENT_BUFFER));
+
+    tokio::spawn(auth::run_session_cleanup_loop(pool.clone()));
+    tokio::spawn(events::run_event_listener(pool.clone(), bus.clone()));
+    tokio::spawn(events::run_market_event_cleanup_loop(pool.clone()));
+    tokio::spawn(serviceauth::run_service_auth_loop(
+        pool.clone(),
+        service_keys.clone(),
+    ));
+    tokio::spawn(handlersorders::run_matching_loop(pool.clone(), bus.clone()));
+    tokio::spawn(handlersleaderboard::run_snapshot_loop(pool.clone()));
+
+    let addr = config.bind_address.clone();
     println!("Server running on http://{}", addr);
+    let config = web::Data::new(config);
 
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone())) // <- Inject pool
+            .app_data(service_keys.clone())
+            .app_data(config.clone())
+            .app_data(bus.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(|e, _| apierror::ApiError::MalformedBody(e.to_string()).into()),
+            )
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(|_, _| apierror::ApiError::RouteNotFound.into()),
+            )
             .route("/api/register", web::post().to(handlers::register_handler))
             .route("/api/login", web::post().to(handlers::login_handler))
             .route("/api/logout", web::post().to(handlers::logout_handler))
@@ -54,10 +107,53 @@ async fn main() -> std::io::Result<()> {
                 "/api/fetch/cryptospecific",
                 web::post().to(handlerscrypto::fetchstockspecific),
             )
+            .route(
+                "/api/fetch/candles",
+                web::post().to(handlerscrypto::fetchcandles),
+            )
+            .route("/api/fetch/fees", web::post().to(handlersfees::fetchfees))
             .route(
                 "/api/crypto/buycrypto",
                 web::post().to(handlerscryptoapi::buycrypto),
             )
+            .route(
+                "/api/crypto/sellcrypto",
+                web::post().to(handlerscryptoapi::sellcrypto),
+            )
+            .route(
+                "/api/portfolios",
+                web::post().to(handlersportfolio::listportfolios),
+            )
+            .route(
+                "/api/portfolios/{id}",
+                web::post().to(handlersportfolio::portfoliodetail),
+            )
+            .route(
+                "/api/portfolio/summary",
+                web::post().to(handlersportfolio::portfoliosummary),
+            )
+            .route(
+                "/api/portfolio/history",
+                web::post().to(handlersportfolio::portfoliohistory),
+            )
+            .route(
+                "/api/leaderboard",
+                web::post().to(handlersleaderboard::getleaderboard),
+            )
+            .route(
+                "/api/orders/place",
+                web::post().to(handlersorders::placeorder),
+            )
+            .route(
+                "/api/orders/list",
+                web::post().to(handlersorders::listorders),
+            )
+            .route(
+                "/api/orders/cancel",
+                web::post().to(handlersorders::cancelorder),
+            )
+            .route("/api/ws", web::get().to(handlersws::websocket))
+            .route("/api/events", web::get().to(handlerssse::eventstream))
             .route(
                 "/api/middlewear/changeprice",
                 web::post().to(handlers::change_price_handler),
@@ -70,6 +166,29 @@ async fn main() -> std::io::Result<()> {
                 "/api/root/removecrypto",
                 web::post().to(handlers::removecrypto),
             )
+            .route("/api/root/setfees", web::post().to(handlersfees::setfees))
+            .route(
+                "/api/root/removefees",
+                web::post().to(handlersfees::removefees),
+            )
+            .route(
+                "/api/root/adjustbalance",
+                web::post().to(handlersportfolio::adjustbalance),
+            )
+            .route(
+                "/api/root/reconcile",
+                web::post().to(handlersportfolio::reconcileledger),
+            )
+            .route(
+                "/api/root/grantrole",
+                web::post().to(handlersroles::grantrole),
+            )
+            .route(
+                "/api/root/revokerole",
+                web::post().to(handlersroles::revokerole),
+            )
+            .route("/api/root/roles", web::post().to(handlersroles::listroles))
+            .default_service(web::to(apierror::route_not_found))
     })
     .bind(addr)?
     .run()
@@ -0,0 +1,165 @@
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use std::fmt;
+
+/// The scripts in `server/migrations`, built into the binary.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Why the database can't be used by this build as it is.
+#[derive(Debug)]
+pub enum SchemaError {
+    /// Migrations this build has that the database doesn't.
+    Pending(Vec<i64>),
+    /// A migration failed partway and the database needs looking at.
+    Dirty(i64),
+    /// An applied migration's script has been edited since.
+    Modified(i64),
+    /// The database has a migration this build doesn't know, so it's newer.
+    Unknown(i64),
+    Database(MigrateError),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Pending(versions) => write!(
+                f,
+                "Database schema is out of date, {} migrations are pending. Run `server migrate`",
+                versions.len()
+            ),
+            SchemaError::Dirty(version) => write!(
+                f,
+                "Migration {} failed partway, fix the database by hand and run `server migrate`",
+                version
+            ),
+            SchemaError::Modified(version) => {
+                write!(f, "Migration {} was changed after it was applied", version)
+            }
+            SchemaError::Unknown(version) => write!(
+                f,
+                "Database has migration {} which this server doesn't know, the server is older than the schema",
+                version
+            ),
+            SchemaError::Database(e) => write!(f, "Migration error: {}", e),
+        }
+    }
+}
+
+impl From<MigrateError> for SchemaError {
+    fn from(e: MigrateError) -> Self {
+        SchemaError::Database(e)
+    }
+}
+
+impl From<sqlx::Error> for SchemaError {
+    fn from(e: sqlx::Error) -> Self {
+        SchemaError::Database(e.into())
+    }
+}
+
+/// Checksums of the migrations applied to the database, by version.
+async fn applied_migrations(pool: &PgPool) -> Result<HashMap<i64, Vec<u8>>, SchemaError> {
+    // A database nothing was ever applied to has no table to read
+    let tracked: bool =
+        sqlx::query("SELECT to_regclass('_sqlx_migrations') IS NOT NULL AS tracked")
+            .fetch_one(pool)
+            .await?
+            .try_get("tracked")?;
+    if !tracked {
+        return Ok(HashMap::new());
+    }
+
+    let mut conn = pool.acquire().await?;
+    if let Some(version) = conn.dirty_version().await? {
+        return Err(SchemaError::Dirty(version));
+    }
+    Ok(conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| (m.version, m.checksum.into_owned()))
+        .collect())
+}
+
+/// Versions this build has that the database hasn't applied yet, after checking
+/// that everything it did apply is known and unchanged.
+async fn pending_migrations(pool: &PgPool) -> Result<Vec<i64>, SchemaError> {
+    let applied = applied_migrations(pool).await?;
+    let mut pending = Vec::new();
+    for migration in MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+    {
+        match applied.get(&migration.version) {
+            Some(checksum) if *checksum != *migration.checksum => {
+                return Err(SchemaError::Modified(migration.version));
+            }
+            Some(_) => {}
+            None => pending.push(migration.version),
+        }
+    }
+    if let Some(version) = applied
+        .keys()
+        .filter(|version| MIGRATOR.iter().all(|m| m.version != **version))
+        .min()
+    {
+        return Err(SchemaError::Unknown(*version));
+    }
+    Ok(pending)
+}
+
+/// Called at startup, so the server never runs against a schema it wasn't built for.
+pub async fn check_schema(pool: &PgPool) -> Result<(), SchemaError> {
+    let pending = pending_migrations(pool).await?;
+    if !pending.is_empty() {
+        return Err(SchemaError::Pending(pending));
+    }
+    Ok(())
+}
+
+/// Applies every pending migration.
+pub async fn migrate_up(pool: &PgPool) -> Result<(), SchemaError> {
+    let pending = pending_migrations(pool).await?;
+    MIGRATOR.run(pool).await?;
+    if pending.is_empty() {
+        println!("Schema is up to date");
+    }
+    for version in pending {
+        println!("Applied migration {}", version);
+    }
+    Ok(())
+}
+
+/// Reverts the most recently applied migration.
+pub async fn migrate_down(pool: &PgPool) -> Result<(), SchemaError> {
+    let applied = applied_migrations(pool).await?;
+    let Some(latest) = applied.keys().max().copied() else {
+        println!("No migrations to revert");
+        return Ok(());
+    };
+    let target = applied.keys().filter(|v| **v < latest).max().copied();
+    MIGRATOR.undo(pool, target.unwrap_or(0)).await?;
+    println!("Reverted migration {}", latest);
+    Ok(())
+}
+
+/// Prints every migration and whether it has been applied.
+pub async fn migrate_status(pool: &PgPool) -> Result<(), SchemaError> {
+    let applied = applied_migrations(pool).await?;
+    for migration in MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+    {
+        let state = if applied.contains_key(&migration.version) {
+            "applied"
+        } else {
+            "pending"
+        };
+        println!(
+            "{:>4} {:<8} {}",
+            migration.version, state, migration.description
+        );
+    }
+    Ok(())
+}
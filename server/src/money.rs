@@ -0,0 +1,71 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+
+/// Cash, prices and fees are kept to the cent, in `NUMERIC(20, 2)` columns.
+pub const MONEY_SCALE: u32 = 2;
+/// Coins can be traded down to a hundred-millionth, in `NUMERIC(20, 8)` columns.
+pub const QUANTITY_SCALE: u32 = 8;
+
+/// Both kinds of column hold 20 digits.
+const MAX_DIGITS: i128 = 99_999_999_999_999_999_999;
+
+fn max_money() -> Decimal {
+    Decimal::from_i128_with_scale(MAX_DIGITS, MONEY_SCALE)
+}
+
+fn max_quantity() -> Decimal {
+    Decimal::from_i128_with_scale(MAX_DIGITS, QUANTITY_SCALE)
+}
+
+/// An amount too large to store, or to work out at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overflow;
+
+/// Turns the result of a `checked_*` operation into an amount of money, or
+/// `Overflow` if it overflowed or won't fit in a money column.
+pub fn checked(value: Option<Decimal>) -> Result<Decimal, Overflow> {
+    value.filter(|v| v.abs() <= max_money()).ok_or(Overflow)
+}
+
+/// Rounds up to whole cents, for what a player pays.
+pub fn cents_up(value: Decimal) -> Decimal {
+    value.round_dp_with_strategy(MONEY_SCALE, RoundingStrategy::ToPositiveInfinity)
+}
+
+/// Rounds down to whole cents, for what a player gets.
+pub fn cents_down(value: Decimal) -> Decimal {
+    value.round_dp_with_strategy(MONEY_SCALE, RoundingStrategy::ToNegativeInfinity)
+}
+
+/// Checks a quantity of coins given in a request.
+pub fn check_quantity(amount: Decimal) -> Result<(), &'static str> {
+    if amount <= Decimal::ZERO {
+        return Err("Amount must be positive");
+    }
+    if amount.normalize().scale() > QUANTITY_SCALE {
+        return Err("Amount can have at most 8 decimal places");
+    }
+    if amount > max_quantity() {
+        return Err("Amount is too large");
+    }
+    Ok(())
+}
+
+/// Checks an amount of money or a price given in a request. Zero is only
+/// allowed where `allow_zero` says so.
+pub fn check_money(value: Decimal, allow_zero: bool) -> Result<(), &'static str> {
+    if value < Decimal::ZERO || (value.is_zero() && !allow_zero) {
+        return Err("Amount must be positive");
+    }
+    check_cents(value)
+}
+
+/// Checks a signed amount of money, such as a balance adjustment.
+pub fn check_cents(value: Decimal) -> Result<(), &'static str> {
+    if value.normalize().scale() > MONEY_SCALE {
+        return Err("Amounts of money can have at most 2 decimal places");
+    }
+    if value.abs() > max_money() {
+        return Err("Amount is too large");
+    }
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use rand::rngs::OsRng;
+
+/// How a password compared to what is stored for it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PasswordMatch {
+    Invalid,
+    Valid,
+    /// Matched a row from before hashing, which should now be replaced with a hash.
+    ValidPlaintext,
+}
+
+/// Hashes a password with argon2 and a fresh salt, in PHC string format.
+/// Runs on the blocking pool since the KDF is deliberately slow.
+pub async fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .expect("password hashing panicked")
+}
+
+/// Checks a password against a stored argon2 hash, or against the plaintext
+/// older rows still hold.
+pub async fn verify_password(password: &str, stored: &str) -> PasswordMatch {
+    let Ok(hash) = PasswordHash::new(stored) else {
+        return if password == stored {
+            PasswordMatch::ValidPlaintext
+        } else {
+            PasswordMatch::Invalid
+        };
+    };
+
+    let password = password.to_owned();
+    let hash = hash.serialize();
+    tokio::task::spawn_blocking(move || {
+        let valid = Argon2::default()
+            .verify_password(password.as_bytes(), &hash.password_hash())
+            .is_ok();
+        if valid {
+            PasswordMatch::Valid
+        } else {
+            PasswordMatch::Invalid
+        }
+    })
+    .await
+    .expect("password verification panicked")
+}
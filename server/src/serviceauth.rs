@@ -0,0 +1,248 @@
+use crate::apierror::ApiError;
+use actix_web::HttpRequest;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const KEY_ID_HEADER: &str = "X-Service-Key";
+pub const TIMESTAMP_HEADER: &str = "X-Service-Timestamp";
+pub const NONCE_HEADER: &str = "X-Service-Nonce";
+pub const SIGNATURE_HEADER: &str = "X-Service-Signature";
+
+/// How far a request's timestamp may be from the server clock. Nonces only have
+/// to be remembered for this long, since anything older is refused anyway.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+/// How often the key file is checked for changes and old nonces are deleted.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Why a service request was refused.
+#[derive(Debug)]
+pub enum ServiceAuthError {
+    MissingHeader(&'static str),
+    UnknownKey,
+    BadSignature,
+    BadNonce,
+    Stale,
+    Replayed,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for ServiceAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceAuthError::MissingHeader(name) => write!(f, "Missing {} header", name),
+            ServiceAuthError::UnknownKey => write!(f, "Unknown service key"),
+            ServiceAuthError::BadSignature => write!(f, "Invalid signature"),
+            ServiceAuthError::BadNonce => write!(f, "Nonce must be 16 to 64 characters"),
+            ServiceAuthError::Stale => write!(f, "Request timestamp out of range"),
+            ServiceAuthError::Replayed => write!(f, "Request already seen"),
+            ServiceAuthError::Database(e) => write!(f, "DB error: {}", e),
+        }
+    }
+}
+
+impl From<ServiceAuthError> for ApiError {
+    fn from(e: ServiceAuthError) -> Self {
+        match e {
+            ServiceAuthError::Database(e) => {
+                ApiError::Internal(format!("DB error (service auth): {}", e))
+            }
+            e => ApiError::ServiceUnauthorized(e.to_string()),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ServiceAuthError {
+    fn from(e: sqlx::Error) -> Self {
+        ServiceAuthError::Database(e)
+    }
+}
+
+/// What gets signed: every part of the request a replay or a tamper could change.
+pub fn signing_payload(
+    timestamp: &str,
+    nonce: &str,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Vec<u8> {
+    let mut payload = format!("{}\n{}\n{}\n{}\n", timestamp, nonce, method, path).into_bytes();
+    payload.extend_from_slice(body);
+    payload
+}
+
+/// Shared keys for internal services, by key id. Several can be active at once so
+/// a key can be rotated by adding the new one, moving the services over and then
+/// removing the old one.
+///
+/// Keys come from `service_keys_file` in the config, one `<id> <secret>` per
+/// line, which is re-read whenever it changes. Without it a single key is read
+/// from `SERVICE_KEY_ID` and `SERVICE_KEY`.
+pub struct ServiceKeys {
+    file: Option<PathBuf>,
+    keys: RwLock<HashMap<String, Vec<u8>>>,
+    loaded_at: RwLock<Option<SystemTime>>,
+}
+
+impl ServiceKeys {
+    pub fn new(file: Option<PathBuf>) -> ServiceKeys {
+        let service_keys = ServiceKeys {
+            file,
+            keys: RwLock::new(HashMap::new()),
+            loaded_at: RwLock::new(None),
+        };
+
+        match &service_keys.file {
+            Some(_) => service_keys.reload(),
+            None => {
+                if let (Ok(id), Ok(secret)) = (
+                    std::env::var("SERVICE_KEY_ID"),
+                    std::env::var("SERVICE_KEY"),
+                ) {
+                    service_keys
+                        .keys
+                        .write()
+                        .unwrap()
+                        .insert(id, secret.into_bytes());
+                }
+            }
+        }
+
+        if service_keys.keys.read().unwrap().is_empty() {
+            eprintln!("No service keys configured, price updates will be refused");
+        }
+        service_keys
+    }
+
+    /// Re-reads the key file if it changed since it was last loaded. A file that
+    /// can't be read keeps the keys that are already loaded.
+    fn reload(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let modified = match std::fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                eprintln!("Can't read service keys from {}: {}", path.display(), e);
+                return;
+            }
+        };
+        if *self.loaded_at.read().unwrap() == Some(modified) {
+            return;
+        }
+
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("Can't read service keys from {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let keys: HashMap<String, Vec<u8>> = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let (id, secret) = line.split_once(char::is_whitespace)?;
+                Some((id.to_string(), secret.trim().as_bytes().to_vec()))
+            })
+            .collect();
+
+        println!("Loaded {} service keys from {}", keys.len(), path.display());
+        *self.keys.write().unwrap() = keys;
+        *self.loaded_at.write().unwrap() = Some(modified);
+    }
+
+    /// Checks that a request was signed with one of the keys, is recent, and
+    /// hasn't been seen before. Returns the id of the key it was signed with.
+    pub async fn verify(
+        &self,
+        pool: &PgPool,
+        req: &HttpRequest,
+        body: &[u8],
+    ) -> Result<String, ServiceAuthError> {
+        let header = |name: &'static str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or(ServiceAuthError::MissingHeader(name))
+        };
+        let key_id = header(KEY_ID_HEADER)?;
+        let timestamp = header(TIMESTAMP_HEADER)?;
+        let nonce = header(NONCE_HEADER)?;
+        if !(16..=64).contains(&nonce.len()) {
+            return Err(ServiceAuthError::BadNonce);
+        }
+        let signature =
+            hex::decode(header(SIGNATURE_HEADER)?).map_err(|_| ServiceAuthError::BadSignature)?;
+
+        let mut mac = {
+            let keys = self.keys.read().unwrap();
+            let key = keys.get(key_id).ok_or(ServiceAuthError::UnknownKey)?;
+            HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length")
+        };
+        mac.update(&signing_payload(
+            timestamp,
+            nonce,
+            req.method().as_str(),
+            req.path(),
+            body,
+        ));
+        mac.verify_slice(&signature)
+            .map_err(|_| ServiceAuthError::BadSignature)?;
+
+        // Only trusted once the signature shows the timestamp wasn't altered
+        let sent_at = timestamp
+            .parse::<u64>()
+            .map_err(|_| ServiceAuthError::Stale)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("clock before 1970")
+            .as_secs();
+        if now.abs_diff(sent_at) > MAX_CLOCK_SKEW.as_secs() {
+            return Err(ServiceAuthError::Stale);
+        }
+
+        let first_use = sqlx::query(
+            "INSERT INTO service_nonces (key_id, nonce) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(key_id)
+        .bind(nonce)
+        .execute(pool)
+        .await?
+        .rows_affected()
+            == 1;
+        if !first_use {
+            return Err(ServiceAuthError::Replayed);
+        }
+
+        Ok(key_id.to_string())
+    }
+}
+
+/// Background task that picks up changes to the key file and forgets nonces old
+/// enough that their requests would be refused as stale anyway.
+pub async fn run_service_auth_loop(pool: PgPool, keys: actix_web::web::Data<ServiceKeys>) {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        keys.reload();
+
+        let result = sqlx::query(
+            "DELETE FROM service_nonces WHERE seen_at < NOW() - 2 * $1 * INTERVAL '1 second'",
+        )
+        .bind(MAX_CLOCK_SKEW.as_secs_f64())
+        .execute(&pool)
+        .await;
+        if let Err(e) = result {
+            eprintln!("Error deleting old service nonces: {}", e);
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use crate::migrate;
+use sqlx::PgPool;
+use sqlx::postgres::PgPoolOptions;
+
+/// Runs against the database in `TEST_DATABASE_URL` and is skipped when it isn't set.
+pub async fn test_pool() -> Option<PgPool> {
+    let Ok(database_url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL not set, skipping");
+        return None;
+    };
+    let pool = PgPoolOptions::new()
+        .max_connections(20)
+        .connect(&database_url)
+        .await
+        .expect("Failed to connect to test DB");
+
+    migrate::MIGRATOR
+        .run(&pool)
+        .await
+        .expect("Failed to migrate test DB");
+    Some(pool)
+}
@@ -0,0 +1,326 @@
+use crate::apierror::ApiError;
+use crate::money;
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest, web};
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+/// A JSON body that has been checked against the `#[validate]` rules on `T`.
+/// Use it in place of `web::Json<T>`.
+#[derive(Debug)]
+pub struct Valid<T>(pub T);
+
+impl<T> Deref for Valid<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for Valid<T> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<T>::from_request(req, payload);
+        Box::pin(async move {
+            let data = json.await?.into_inner();
+            validate(&data).map_err(ApiError::from)?;
+            Ok(Valid(data))
+        })
+    }
+}
+
+/// Checks a value against its `#[validate]` rules, for bodies that aren't read
+/// through `Valid`.
+pub fn validate<T: Validate>(data: &T) -> Result<(), InvalidRequest> {
+    data.validate().map_err(InvalidRequest::from)
+}
+
+/// A request that broke one or more rules, answered with a 400 listing every
+/// field that was wrong and why.
+#[derive(Debug)]
+pub struct InvalidRequest {
+    pub fields: BTreeMap<String, Vec<String>>,
+}
+
+impl From<ValidationErrors> for InvalidRequest {
+    fn from(errors: ValidationErrors) -> Self {
+        let fields = errors
+            .field_errors()
+            .into_iter()
+            .map(|(field, errors)| (field.to_string(), errors.iter().map(describe).collect()))
+            .collect();
+        InvalidRequest { fields }
+    }
+}
+
+impl fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid request: ")?;
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .map(|(field, problems)| format!("{} {}", field, problems.join(", ")))
+            .collect();
+        write!(f, "{}", fields.join("; "))
+    }
+}
+
+/// A readable message for a broken rule. Our own rules carry one, the built in
+/// ones are described from their parameters.
+fn describe(error: &ValidationError) -> String {
+    if let Some(message) = &error.message {
+        return message.to_string();
+    }
+    let param = |name: &str| error.params.get(name).map(|value| value.to_string());
+    match error.code.as_ref() {
+        "length" => match (param("min"), param("max")) {
+            (Some(min), Some(max)) => format!("must be {} to {} characters", min, max),
+            (Some(min), None) => format!("must be at least {} characters", min),
+            (None, Some(max)) => format!("must be at most {} characters", max),
+            (None, None) => "has the wrong length".to_string(),
+        },
+        "range" => match (param("min"), param("max")) {
+            (Some(min), Some(max)) => format!("must be between {} and {}", min, max),
+            (Some(min), None) => format!("must be at least {}", min),
+            (None, Some(max)) => format!("must be at most {}", max),
+            (None, None) => "is out of range".to_string(),
+        },
+        "email" => "must be an email address".to_string(),
+        code => format!("is invalid ({})", code),
+    }
+}
+
+fn rule(code: &'static str, message: &'static str) -> ValidationError {
+    ValidationError::new(code).with_message(Cow::Borrowed(message))
+}
+
+/// Names of coins and portfolios: something other than whitespace, and nothing
+/// that would mess up a terminal.
+pub fn name(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(rule("name", "must not be blank"));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(rule("name", "must not contain control characters"));
+    }
+    Ok(())
+}
+
+/// A positive number of coins, see `money::check_quantity`.
+pub fn quantity(value: &Decimal) -> Result<(), ValidationError> {
+    money::check_quantity(*value).map_err(|reason| rule("quantity", reason))
+}
+
+/// A positive price or amount of money.
+pub fn price(value: &Decimal) -> Result<(), ValidationError> {
+    money::check_money(*value, false).map_err(|reason| rule("money", reason))
+}
+
+/// An amount of money that may be zero, like a fee.
+pub fn fee(value: &Decimal) -> Result<(), ValidationError> {
+    money::check_money(*value, true).map_err(|reason| rule("money", reason))
+}
+
+/// A non-zero change to a balance, in either direction.
+pub fn adjustment(value: &Decimal) -> Result<(), ValidationError> {
+    if value.is_zero() {
+        return Err(rule("money", "Amount must not be zero"));
+    }
+    money::check_cents(*value).map_err(|reason| rule("money", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::{AddPortfolioStruct, CreateCryptoStruct, RegisterDataStruct};
+    use crate::handlerscryptoapi::{BuyCryptoData, SellCryptoData};
+    use crate::handlersfees::SetFeesData;
+    use crate::handlersleaderboard::LeaderboardData;
+    use crate::handlersorders::PlaceOrderData;
+    use crate::handlersportfolio::{AdjustBalanceData, PortfolioHistoryData};
+    use actix_web::test::{TestRequest, call_service, init_service, read_body_json};
+    use actix_web::{App, HttpResponse};
+    use serde_json::json;
+
+    fn check<T: DeserializeOwned + Validate>(
+        body: serde_json::Value,
+    ) -> Result<(), InvalidRequest> {
+        let data: T = serde_json::from_value(body).expect("body should deserialize");
+        validate(&data)
+    }
+
+    fn invalid_fields<T: DeserializeOwned + Validate>(body: serde_json::Value) -> Vec<String> {
+        match check::<T>(body) {
+            Ok(()) => Vec::new(),
+            Err(e) => e.fields.into_keys().collect(),
+        }
+    }
+
+    fn buy(amount: serde_json::Value) -> serde_json::Value {
+        json!({
+            "portfolio_id": 1,
+            "portfoliopassword": "password",
+            "crypto_to_buy": "BTC",
+            "amount": amount,
+        })
+    }
+
+    #[test]
+    fn accepts_a_valid_buy() {
+        assert!(check::<BuyCryptoData>(buy(json!("0.25"))).is_ok());
+        assert!(check::<BuyCryptoData>(buy(json!(3))).is_ok());
+    }
+
+    #[test]
+    fn rejects_negative_and_zero_amounts() {
+        assert_eq!(
+            invalid_fields::<BuyCryptoData>(buy(json!(-100))),
+            ["amount"]
+        );
+        assert_eq!(invalid_fields::<BuyCryptoData>(buy(json!("0"))), ["amount"]);
+
+        let sell = json!({
+            "portfolio_id": 1,
+            "portfoliopassword": "password",
+            "crypto_to_sell": "BTC",
+            "amount": "-0.5",
+        });
+        assert_eq!(invalid_fields::<SellCryptoData>(sell), ["amount"]);
+    }
+
+    #[test]
+    fn rejects_amounts_that_are_too_precise_or_too_large() {
+        assert_eq!(
+            invalid_fields::<BuyCryptoData>(buy(json!("0.000000001"))),
+            ["amount"]
+        );
+        assert_eq!(
+            invalid_fields::<BuyCryptoData>(buy(json!("10000000000000"))),
+            ["amount"]
+        );
+    }
+
+    #[test]
+    fn rejects_history_pages_past_the_end() {
+        let history = |page: i64| {
+            json!({
+                "portfolio_id": 1,
+                "portfoliopassword": "password",
+                "page": page,
+                "per_page": 200,
+            })
+        };
+        assert!(check::<PortfolioHistoryData>(history(3)).is_ok());
+        assert_eq!(
+            invalid_fields::<PortfolioHistoryData>(history(i64::MAX)),
+            ["page"]
+        );
+    }
+
+    #[test]
+    fn rejects_leaderboard_pages_past_the_end() {
+        assert!(check::<LeaderboardData>(json!({ "page": 2, "per_page": 100 })).is_ok());
+        assert_eq!(
+            invalid_fields::<LeaderboardData>(json!({ "page": i64::MAX })),
+            ["page"]
+        );
+    }
+
+    #[test]
+    fn rejects_zero_and_negative_prices() {
+        for price in ["0", "-5", "0.001"] {
+            let body = json!({ "name": "NEW", "price": price });
+            assert_eq!(invalid_fields::<CreateCryptoStruct>(body), ["price"]);
+        }
+        assert!(check::<CreateCryptoStruct>(json!({ "name": "NEW", "price": "0.75" })).is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_and_oversized_strings() {
+        let huge = "x".repeat(1024 * 1024);
+        let body = json!({ "name": huge, "price": 10 });
+        assert_eq!(invalid_fields::<CreateCryptoStruct>(body), ["name"]);
+        let body = json!({ "name": "   ", "price": 10 });
+        assert_eq!(invalid_fields::<CreateCryptoStruct>(body), ["name"]);
+        let body = json!({ "name": "bad\u{1b}[2J", "price": 10 });
+        assert_eq!(invalid_fields::<CreateCryptoStruct>(body), ["name"]);
+
+        let body = json!({ "email": "not an email", "password": huge });
+        assert_eq!(
+            invalid_fields::<RegisterDataStruct>(body),
+            ["email", "password"]
+        );
+        let body = json!({ "name": "", "password": "short" });
+        assert_eq!(
+            invalid_fields::<AddPortfolioStruct>(body),
+            ["name", "password"]
+        );
+    }
+
+    #[test]
+    fn lists_every_invalid_field() {
+        let body = json!({
+            "portfolio_id": 0,
+            "portfoliopassword": "password",
+            "crypto": "BTC",
+            "side": "buy",
+            "amount": "-1",
+            "limit_price": "0",
+        });
+        assert_eq!(
+            invalid_fields::<PlaceOrderData>(body),
+            ["amount", "limit_price", "portfolio_id"]
+        );
+
+        let body = json!({ "flat_fee": "-1", "percent_bps": 10001, "spread_bps": -1 });
+        assert_eq!(
+            invalid_fields::<SetFeesData>(body),
+            ["flat_fee", "percent_bps", "spread_bps"]
+        );
+
+        let body = json!({ "portfolio_id": 1, "amount": "0", "note": "" });
+        assert_eq!(
+            invalid_fields::<AdjustBalanceData>(body),
+            ["amount", "note"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn responds_with_400_and_the_invalid_fields() {
+        let app = init_service(App::new().route(
+            "/buy",
+            web::post().to(|_: Valid<BuyCryptoData>| async { HttpResponse::Ok().finish() }),
+        ))
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/buy")
+            .set_json(buy(json!(-100)))
+            .to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), 400);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["error"]["code"], "validation_failed");
+        assert_eq!(
+            body["error"]["fields"]["amount"][0],
+            "Amount must be positive"
+        );
+
+        let request = TestRequest::post()
+            .uri("/buy")
+            .set_json(buy(json!(1)))
+            .to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), 200);
+    }
+}